@@ -1,12 +1,331 @@
+use rand::RngCore;
 use rusqlite::Connection;
+use crate::protocol::{SessionCache, VaultFile, SESSION_CACHE_CAPACITY, SESSION_FLUSH_BATCH};
+use std::collections::HashMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc as mpsc_std;
 use std::sync::Mutex;
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
 use tokio_tungstenite::tungstenite::protocol::Message;
 
+pub const VAULT_LOCKED: &str = "Vault is locked";
+const DB_STOPPED: &str = "Database worker stopped";
+
+/// How long dirty sessions may sit in the cache once the queue goes quiet.
+const SESSION_FLUSH_DELAY: Duration = Duration::from_millis(500);
+
+/// What the database thread owns: the connection slot, which is `None` while the vault
+/// is locked or has not been opened yet, and the sessions cached from it.
+struct DbWorker {
+    slot: Option<Connection>,
+    sessions: SessionCache,
+}
+
+impl DbWorker {
+    fn flush_sessions(&mut self) -> Result<(), String> {
+        match self.slot.as_ref() {
+            Some(conn) => self.sessions.flush(conn),
+            None => Ok(()),
+        }
+    }
+}
+
+type DbJob = Box<dyn FnOnce(&mut DbWorker) + Send>;
+
+/// Handle to the thread that owns the vault connection. Database and ratchet work is
+/// queued there instead of running on the IPC threads behind a mutex, and a job that
+/// panics fails its own request without taking the connection down with it.
 pub struct DbState {
-    pub conn: Mutex<Option<Connection>>,
+    jobs: mpsc_std::Sender<DbJob>,
+    /// Set when an unlocked vault is closed by `vault_lock` or the idle timer, so commands
+    /// can tell the user to re-enter the passphrase rather than that nothing was set up.
+    pub locked: AtomicBool,
+    pub last_activity: Mutex<Instant>,
+    /// The file behind the open connection: the vault, or the decoy in its slot.
+    pub vault_file: Mutex<Option<VaultFile>>,
+}
+
+impl DbState {
+    pub fn spawn() -> Self {
+        let (jobs, queue) = mpsc_std::channel::<DbJob>();
+        std::thread::Builder::new()
+            .name("entropy-db".to_string())
+            .spawn(move || {
+                let mut worker = DbWorker { slot: None, sessions: SessionCache::new(SESSION_CACHE_CAPACITY) };
+                loop {
+                    let job = if worker.sessions.dirty_len() == 0 {
+                        match queue.recv() {
+                            Ok(job) => job,
+                            Err(_) => break,
+                        }
+                    } else {
+                        match queue.recv_timeout(SESSION_FLUSH_DELAY) {
+                            Ok(job) => job,
+                            Err(mpsc_std::RecvTimeoutError::Timeout) => {
+                                // A failed flush stays dirty and is retried on the next one.
+                                let _ = worker.flush_sessions();
+                                continue;
+                            }
+                            Err(mpsc_std::RecvTimeoutError::Disconnected) => break,
+                        }
+                    };
+                    job(&mut worker);
+                    if worker.sessions.dirty_len() >= SESSION_FLUSH_BATCH {
+                        let _ = worker.flush_sessions();
+                    }
+                }
+                let _ = worker.flush_sessions();
+            })
+            .expect("failed to start database thread");
+
+        Self {
+            jobs,
+            locked: AtomicBool::new(false),
+            last_activity: Mutex::new(Instant::now()),
+            vault_file: Mutex::new(None),
+        }
+    }
+
+    fn submit<T, F>(&self, f: F) -> Result<oneshot::Receiver<Result<T, String>>, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut DbWorker) -> Result<T, String> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |worker| {
+                let outcome = catch_unwind(AssertUnwindSafe(|| f(worker)))
+                    .unwrap_or_else(|_| Err("Database operation panicked".to_string()));
+                let _ = reply.send(outcome);
+            }))
+            .map_err(|_| DB_STOPPED.to_string())?;
+        Ok(result)
+    }
+
+    /// Wraps slot work so cached sessions are written to the vault they came from and
+    /// dropped before it can be replaced or closed.
+    fn slot_job<T, F>(f: F) -> impl FnOnce(&mut DbWorker) -> Result<T, String> + Send + 'static
+    where
+        F: FnOnce(&mut Option<Connection>) -> Result<T, String> + Send + 'static,
+    {
+        move |worker| {
+            worker.flush_sessions()?;
+            worker.sessions.clear();
+            f(&mut worker.slot)
+        }
+    }
+
+    /// Runs `f` on the database thread with the connection slot itself, for work that
+    /// opens, replaces or closes the vault.
+    pub async fn with_slot<T, F>(&self, f: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Option<Connection>) -> Result<T, String> + Send + 'static,
+    {
+        self.submit(Self::slot_job(f))?.await.map_err(|_| DB_STOPPED.to_string())?
+    }
+
+    /// Same as [`Self::with_slot`] for callers that are not on the async runtime.
+    pub fn with_slot_blocking<T, F>(&self, f: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Option<Connection>) -> Result<T, String> + Send + 'static,
+    {
+        self.submit(Self::slot_job(f))?.blocking_recv().map_err(|_| DB_STOPPED.to_string())?
+    }
+
+    /// Runs `f` against the open vault and its session cache, or fails with
+    /// [`Self::unavailable`]. Nothing is flushed first.
+    pub async fn with_sessions<T, F>(&self, f: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection, &mut SessionCache) -> Result<T, String> + Send + 'static,
+    {
+        let unavailable = self.unavailable();
+        self.submit(move |worker| match worker.slot.as_ref() {
+            Some(conn) => f(conn, &mut worker.sessions),
+            None => Err(unavailable),
+        })?
+        .await
+        .map_err(|_| DB_STOPPED.to_string())?
+    }
+
+    /// Runs `f` against the open vault, or fails with [`Self::unavailable`]. Cached
+    /// sessions stay in the cache, so `f` must not read them from the vault table.
+    pub async fn with_conn<T, F>(&self, f: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, String> + Send + 'static,
+    {
+        self.with_sessions(move |conn, _| f(conn)).await
+    }
+
+    /// Like [`Self::with_conn`] for work that reads sessions from the database, such as
+    /// dumps and backups: cached sessions are flushed first so `f` sees them.
+    pub async fn with_conn_flushed<T, F>(&self, f: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, String> + Send + 'static,
+    {
+        self.with_sessions(move |conn, sessions| {
+            sessions.flush(conn)?;
+            f(conn)
+        })
+        .await
+    }
+
+    /// Same as [`Self::with_conn_flushed`] for callers that are not on the async runtime.
+    pub fn with_conn_flushed_blocking<T, F>(&self, f: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, String> + Send + 'static,
+    {
+        let unavailable = self.unavailable();
+        self.submit(move |worker| match worker.slot.as_ref() {
+            Some(conn) => {
+                worker.sessions.flush(conn)?;
+                f(conn)
+            }
+            None => Err(unavailable),
+        })?
+        .blocking_recv()
+        .map_err(|_| DB_STOPPED.to_string())?
+    }
+
+    /// Like [`Self::with_conn_flushed`] for work that writes sessions or the identity
+    /// directly, so the cache is dropped afterwards rather than serving stale copies.
+    pub async fn with_conn_invalidating<T, F>(&self, f: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, String> + Send + 'static,
+    {
+        self.with_sessions(move |conn, sessions| {
+            sessions.flush(conn)?;
+            let result = f(conn);
+            sessions.clear();
+            result
+        })
+        .await
+    }
+
+    /// Error for commands that need the vault while no connection is open.
+    pub fn unavailable(&self) -> String {
+        if self.locked.load(Ordering::SeqCst) {
+            VAULT_LOCKED.to_string()
+        } else {
+            "Vault not initialized".to_string()
+        }
+    }
+
+    /// The file the open connection belongs to, or [`Self::unavailable`].
+    pub fn open_file(&self) -> Result<VaultFile, String> {
+        self.vault_file.lock().unwrap().clone().ok_or_else(|| self.unavailable())
+    }
+
+    pub fn set_open_file(&self, file: Option<VaultFile>) {
+        *self.vault_file.lock().unwrap() = file;
+    }
+
+    pub fn touch(&self) {
+        if let Ok(mut last) = self.last_activity.lock() {
+            *last = Instant::now();
+        }
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.lock().map(|last| last.elapsed()).unwrap_or_default()
+    }
+
+    /// Closes the vault. SQLCipher wipes the key and its cipher context when the
+    /// connection closes. Returns false if it was not open.
+    pub async fn lock_vault(&self) -> Result<bool, String> {
+        let was_open = self.with_slot(|slot| Ok(slot.take().is_some())).await?;
+        if was_open {
+            self.mark_locked();
+        }
+        Ok(was_open)
+    }
+
+    /// Records that the vault was closed and needs the passphrase again.
+    pub fn mark_locked(&self) {
+        self.locked.store(true, Ordering::SeqCst);
+        self.set_open_file(None);
+    }
+
+    pub fn mark_unlocked(&self) {
+        self.locked.store(false, Ordering::SeqCst);
+        self.touch();
+    }
 }
 
+#[cfg(feature = "embedded-tor")]
+pub type EmbeddedTorStart = Result<std::sync::Arc<crate::commands::EmbeddedTor>, String>;
+
+/// The relay connection. One supervisor task at a time owns the socket and reconnects
+/// it; `sender` queues outgoing frames for whichever connection is current.
+#[derive(Default)]
 pub struct NetworkState {
     pub sender: Mutex<Option<mpsc::UnboundedSender<Message>>>,
+    pub supervisor: Mutex<Option<tauri::async_runtime::JoinHandle<()>>>,
+    /// SOCKS credentials used to keep each purpose on its own Tor circuit.
+    pub isolation: Mutex<crate::commands::StreamIsolation>,
+    /// The in-process Tor client once `start_embedded_tor` has bootstrapped it, or the
+    /// error its last bootstrap failed with.
+    #[cfg(feature = "embedded-tor")]
+    pub tor: tokio::sync::Mutex<Option<EmbeddedTorStart>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FileAccess {
+    Read,
+    Write,
+    Directory,
+}
+
+/// How long a picked path stays usable if the operation it was picked for never completes.
+const FILE_GRANT_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Paths the user picked in a native dialog, handed to the webview only as opaque
+/// tokens so a compromised UI cannot name arbitrary files. A token lapses after
+/// [`FILE_GRANT_TTL`] and is revoked once the operation it was picked for succeeds.
+pub struct FileGrants {
+    grants: Mutex<HashMap<String, (PathBuf, FileAccess, Instant)>>,
+    ttl: Duration,
+}
+
+impl Default for FileGrants {
+    fn default() -> Self {
+        Self::with_ttl(FILE_GRANT_TTL)
+    }
+}
+
+impl FileGrants {
+    pub fn with_ttl(ttl: Duration) -> Self {
+        Self { grants: Mutex::new(HashMap::new()), ttl }
+    }
+
+    pub fn grant(&self, path: PathBuf, access: FileAccess) -> String {
+        let mut token = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut token);
+        let token = hex::encode(token);
+        self.grants.lock().unwrap().insert(token.clone(), (path, access, Instant::now()));
+        token
+    }
+
+    pub fn resolve(&self, token: &str, access: FileAccess) -> Result<PathBuf, String> {
+        let mut grants = self.grants.lock().unwrap();
+        grants.retain(|_, (_, _, granted_at)| granted_at.elapsed() < self.ttl);
+        match grants.get(token) {
+            Some((path, granted, _)) if *granted == access => Ok(path.clone()),
+            Some(_) => Err("File token does not allow this operation".to_string()),
+            None => Err("Unknown or expired file token".to_string()),
+        }
+    }
+
+    pub fn revoke(&self, token: &str) {
+        self.grants.lock().unwrap().remove(token);
+    }
 }
@@ -2,7 +2,9 @@ use sha2::{Sha256, Digest};
 use aes_gcm::KeyInit;
 use aes_gcm::aead::Aead;
 use rand::Rng;
+use tauri::State;
 use crate::protocol;
+use crate::app_state::DbState;
 
 #[tauri::command]
 pub fn crypto_sha256(data: Vec<u8>) -> Result<String, String> {
@@ -11,6 +13,8 @@ pub fn crypto_sha256(data: Vec<u8>) -> Result<String, String> {
     Ok(hex::encode(hasher.finalize()))
 }
 
+/// Key attachments were stored under before they moved onto the vault header. Only used
+/// to migrate them; see [`crypto_attachment_key`].
 #[tauri::command]
 pub async fn crypto_pbkdf2(password: String, salt: String) -> Result<Vec<u8>, String> {
     tokio::task::spawn_blocking(move || {
@@ -25,6 +29,23 @@ pub async fn crypto_pbkdf2(password: String, salt: String) -> Result<Vec<u8>, St
     }).await.map_err(|e| e.to_string())?
 }
 
+/// Key for locally stored attachments, kept in the unlocked vault.
+#[tauri::command]
+pub async fn crypto_attachment_key(state: State<'_, DbState>) -> Result<Vec<u8>, String> {
+    state.with_conn(protocol::load_or_create_attachment_key).await
+}
+
+/// The attachment key derived from the vault's Argon2id header, which attachments used
+/// before [`crypto_attachment_key`]. Only valid until the next rekey; used to migrate them.
+#[tauri::command]
+pub async fn crypto_derive_attachment_key(state: State<'_, DbState>, passphrase: String) -> Result<Vec<u8>, String> {
+    let file = state.open_file()?;
+    tokio::task::spawn_blocking(move || {
+        let header = file.header()?.ok_or("Vault has no KDF header")?;
+        header.derive_attachment_key(&passphrase)
+    }).await.map_err(|e| e.to_string())?
+}
+
 #[tauri::command]
 pub fn crypto_encrypt(key: Vec<u8>, plaintext: Vec<u8>) -> Result<String, String> {
     let cipher = aes_gcm::Aes256Gcm::new_from_slice(&key).map_err(|e| format!("{:?}", e))?;
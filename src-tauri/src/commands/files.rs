@@ -0,0 +1,37 @@
+use tauri::State;
+use tauri_plugin_dialog::DialogExt;
+use serde_json::Value;
+use crate::app_state::{FileAccess, FileGrants};
+
+fn granted(grants: &FileGrants, path: Option<tauri_plugin_dialog::FilePath>, access: FileAccess) -> Result<Option<Value>, String> {
+    let Some(path) = path else { return Ok(None) };
+    let path = path.into_path().map_err(|e| e.to_string())?;
+    let display = path.to_string_lossy().to_string();
+    let token = grants.grant(path, access);
+    Ok(Some(serde_json::json!({ "token": token, "path": display })))
+}
+
+// Dialogs block until the user answers, so these must stay off the main thread.
+
+#[tauri::command]
+pub async fn fs_pick_save_file(app: tauri::AppHandle, grants: State<'_, FileGrants>, file_name: String, filter_name: String, extension: String) -> Result<Option<Value>, String> {
+    let path = app.dialog().file()
+        .add_filter(filter_name, &[extension.as_str()])
+        .set_file_name(file_name)
+        .blocking_save_file();
+    granted(&grants, path, FileAccess::Write)
+}
+
+#[tauri::command]
+pub async fn fs_pick_open_file(app: tauri::AppHandle, grants: State<'_, FileGrants>, filter_name: String, extension: String) -> Result<Option<Value>, String> {
+    let path = app.dialog().file()
+        .add_filter(filter_name, &[extension.as_str()])
+        .blocking_pick_file();
+    granted(&grants, path, FileAccess::Read)
+}
+
+#[tauri::command]
+pub async fn fs_pick_directory(app: tauri::AppHandle, grants: State<'_, FileGrants>) -> Result<Option<Value>, String> {
+    let path = app.dialog().file().blocking_pick_folder();
+    granted(&grants, path, FileAccess::Directory)
+}
@@ -2,8 +2,16 @@ pub mod crypto;
 pub mod protocol;
 pub mod vault;
 pub mod network;
+pub mod files;
+#[cfg(feature = "embedded-tor")]
+pub mod tor;
+#[cfg(any(test, feature = "embedded-tor"))]
+pub(crate) mod socks_server;
 
 pub use crypto::*;
 pub use protocol::*;
 pub use vault::*;
 pub use network::*;
+pub use files::*;
+#[cfg(feature = "embedded-tor")]
+pub use tor::*;
@@ -1,37 +1,51 @@
-use tauri::{State, Manager};
+use tauri::{State, Manager, Emitter};
 use crate::protocol;
-use crate::app_state::DbState;
+use crate::app_state::{DbState, FileAccess, FileGrants};
 use serde_json::Value;
-use pqcrypto_traits::kem::SecretKey;
 
 #[tauri::command]
-pub fn protocol_establish_session(state: State<'_, DbState>, remote_hash: String, bundle: Value) -> Result<(), String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
-        protocol::establish_outbound_session(conn, &remote_hash, &bundle)
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+pub async fn protocol_establish_session(state: State<'_, DbState>, remote_hash: String, bundle: Value) -> Result<(), String> {
+    state.with_conn_invalidating(move |conn| protocol::establish_outbound_session(conn, &remote_hash, &bundle)).await
+}
+
+#[tauri::command]
+pub async fn protocol_encrypt(state: State<'_, DbState>, remote_hash: String, plaintext: String) -> Result<Value, String> {
+    state.with_sessions(move |conn, sessions| protocol::ratchet_encrypt_cached(conn, sessions, &remote_hash, &plaintext)).await
 }
 
+/// Edits, deletes and reactions are applied here, against the session that authenticated
+/// them, and announced with a `message-updated` event.
 #[tauri::command]
-pub fn protocol_encrypt(state: State<'_, DbState>, remote_hash: String, plaintext: String) -> Result<Value, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
-        protocol::ratchet_encrypt(conn, &remote_hash, &plaintext)
-    } else {
-        Err("Vault not initialized".to_string())
+pub async fn protocol_decrypt(app: tauri::AppHandle, state: State<'_, DbState>, remote_hash: String, msg_obj: Value) -> Result<String, String> {
+    let (plaintext, updated) = state.with_sessions(move |conn, sessions| {
+        let plaintext = protocol::ratchet_decrypt_cached(conn, sessions, &remote_hash, &msg_obj)?;
+        let updated = protocol::apply_incoming_control(conn, &remote_hash, &remote_hash, &plaintext);
+        Ok((plaintext, updated))
+    }).await?;
+    if let Some(updated) = updated {
+        let _ = app.emit("message-updated", updated);
     }
+    Ok(plaintext)
 }
 
+/// Decrypts a drained offline queue in one call. Each entry gets its own result, so a
+/// bad message is reported without failing the rest.
 #[tauri::command]
-pub fn protocol_decrypt(state: State<'_, DbState>, remote_hash: String, msg_obj: Value) -> Result<String, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
-        protocol::ratchet_decrypt(conn, &remote_hash, &msg_obj)
-    } else {
-        Err("Vault not initialized".to_string())
+pub async fn protocol_decrypt_batch(app: tauri::AppHandle, state: State<'_, DbState>, items: Vec<protocol::BatchItem>) -> Result<Vec<protocol::BatchResult>, String> {
+    let (results, updated) = state.with_sessions(move |conn, sessions| {
+        let results = protocol::decrypt_batch(conn, sessions, items)?;
+        let updated: Vec<Value> = results.iter()
+            .filter_map(|r| match (&r.sender, &r.plaintext) {
+                (Some(sender), Some(plaintext)) => protocol::apply_incoming_control(conn, sender, sender, plaintext),
+                _ => None,
+            })
+            .collect();
+        Ok((results, updated))
+    }).await?;
+    for message in updated {
+        let _ = app.emit("message-updated", message);
     }
+    Ok(results)
 }
 
 #[tauri::command]
@@ -40,9 +54,8 @@ pub fn protocol_get_safety_number(me_ik: String, peer_ik: String) -> Result<Stri
 }
 
 #[tauri::command]
-pub fn protocol_init(state: State<'_, DbState>) -> Result<Value, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+pub async fn protocol_init(state: State<'_, DbState>) -> Result<Value, String> {
+    state.with_conn_invalidating(move |conn| {
         let identity = if let Some(identity) = protocol::ProtocolIdentity::load_from_db(conn)? {
             identity
         } else {
@@ -66,25 +79,17 @@ pub fn protocol_init(state: State<'_, DbState>) -> Result<Value, String> {
                 "public_key": pk.public_key
             })).collect::<Vec<_>>()
         }))
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+    }).await
 }
 
 #[tauri::command]
-pub fn protocol_sign(state: State<'_, DbState>, message: String) -> Result<String, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
-        protocol::sign_message(conn, message.as_bytes())
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+pub async fn protocol_sign(state: State<'_, DbState>, message: String) -> Result<String, String> {
+    state.with_conn(move |conn| protocol::sign_message(conn, message.as_bytes())).await
 }
 
 #[tauri::command]
-pub fn protocol_replenish_pre_keys(state: State<'_, DbState>, count: u32) -> Result<Value, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+pub async fn protocol_replenish_pre_keys(state: State<'_, DbState>, count: u32) -> Result<Value, String> {
+    state.with_conn_invalidating(move |conn| {
         let mut identity = protocol::ProtocolIdentity::load_from_db(conn)?.ok_or("No identity")?;
         identity.replenish_pre_keys(count);
         identity.save_to_db(conn)?;
@@ -94,102 +99,73 @@ pub fn protocol_replenish_pre_keys(state: State<'_, DbState>, count: u32) -> Res
                 "public_key": pk.public_key
             })).collect::<Vec<_>>()
         }))
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+    }).await
 }
 
 #[tauri::command]
-pub fn protocol_verify_session(state: State<'_, DbState>, remote_hash: String, verified: bool) -> Result<(), String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
-        protocol::verify_session(conn, &remote_hash, verified)
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+pub async fn protocol_verify_session(state: State<'_, DbState>, remote_hash: String, verified: bool) -> Result<(), String> {
+    state.with_conn_invalidating(move |conn| protocol::verify_session(conn, &remote_hash, verified)).await
 }
 
 #[tauri::command]
-pub fn protocol_secure_vacuum(state: State<'_, DbState>) -> Result<(), String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+pub async fn protocol_secure_vacuum(state: State<'_, DbState>) -> Result<(), String> {
+    state.with_conn(move |conn| {
         conn.execute("VACUUM;", []).map_err(|e| e.to_string())?;
         Ok(())
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+    }).await
 }
 
 #[tauri::command]
-pub fn protocol_encrypt_sealed(
+pub async fn protocol_encrypt_sealed(
     state: State<'_, DbState>,
     remote_public_identity_key: String,
     remote_pq_public_identity_key: String,
     message_body: Value
 ) -> Result<Value, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+    state.with_conn(move |conn| {
         let identity = protocol::ProtocolIdentity::load_from_db(conn)?.ok_or("No identity")?;
-        
+    
         let mut pk_bytes = [0u8; 32];
         pk_bytes.copy_from_slice(&protocol::decode_b64(&remote_public_identity_key)?);
         let recipient_pk = protocol::X25519PublicKey::from(pk_bytes);
 
         protocol::seal_sender(message_body, &identity.identity_keys.public_key, &recipient_pk, &remote_pq_public_identity_key)
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+    }).await
 }
 
 #[tauri::command]
-pub fn protocol_decrypt_sealed(
+pub async fn protocol_decrypt_sealed(
     state: State<'_, DbState>,
     sealed_obj: Value
 ) -> Result<Value, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+    state.with_conn(move |conn| {
         let identity = protocol::ProtocolIdentity::load_from_db(conn)?.ok_or("No identity")?;
-        
-        let mut sk_bytes = [0u8; 32];
-        sk_bytes.copy_from_slice(&protocol::decode_b64(&identity.identity_keys.private_key)?);
-        let my_sk = protocol::StaticSecret::from(sk_bytes);
-
-        let my_pq_sk = pqcrypto_kyber::kyber1024::SecretKey::from_bytes(&protocol::decode_b64(&identity.identity_keys.pq_private_key)?).map_err(|_| "Invalid PQ SK")?;
-
-        let (sender, message) = protocol::unseal_sender(&sealed_obj, &my_sk, &my_pq_sk)?;
+        let (sender, message) = protocol::unseal_with_identity(&identity, &sealed_obj)?;
         Ok(serde_json::json!({
             "sender": sender,
             "message": message
         }))
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+    }).await
 }
 
 #[tauri::command]
-pub fn protocol_encrypt_media(state: State<'_, DbState>, data: Vec<u8>, file_name: String, file_type: String) -> Result<Value, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+pub async fn protocol_encrypt_media(state: State<'_, DbState>, data: Vec<u8>, file_name: String, file_type: String) -> Result<Value, String> {
+    state.with_conn(move |conn| {
         let (ct, bundle) = protocol::encrypt_media(conn, &data, &file_name, &file_type)?;
         Ok(serde_json::json!({
             "ciphertext": hex::encode(ct),
             "bundle": bundle
         }))
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+    }).await
 }
 
 #[tauri::command]
-pub fn protocol_decrypt_media(state: State<'_, DbState>, hex_data: String, bundle: Value) -> Result<Vec<u8>, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+pub async fn protocol_decrypt_media(state: State<'_, DbState>, hex_data: String, bundle: Value) -> Result<Vec<u8>, String> {
+    state.with_conn(move |conn| {
         let ct = hex::decode(hex_data).map_err(|e| e.to_string())?;
         let b: protocol::MediaKeyBundle = serde_json::from_value(bundle).map_err(|e| e.to_string())?;
         protocol::decrypt_media(conn, &ct, &b)
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+    }).await
 }
 
 #[tauri::command]
@@ -207,22 +183,18 @@ pub fn protocol_decrypt_media_chunk(key_b64: String, nonce_b64: String, chunk_in
 }
 
 #[tauri::command]
-pub fn protocol_create_group_distribution(state: State<'_, DbState>, group_id: String) -> Result<Value, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+pub async fn protocol_create_group_distribution(state: State<'_, DbState>, group_id: String) -> Result<Value, String> {
+    state.with_conn(move |conn| {
         let mut stmt = conn.prepare("SELECT state FROM groups WHERE group_id = ?1;").map_err(|e| e.to_string())?;
         let row: String = stmt.query_row([&group_id], |r| r.get(0)).map_err(|e| e.to_string())?;
         let gs: protocol::GroupState = serde_json::from_str(&row).map_err(|e| e.to_string())?;
         protocol::create_group_distribution_message(&gs)
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+    }).await
 }
 
 #[tauri::command]
-pub fn protocol_group_init(state: State<'_, DbState>, group_id: String) -> Result<Value, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+pub async fn protocol_group_init(state: State<'_, DbState>, group_id: String) -> Result<Value, String> {
+    state.with_conn(move |conn| {
         let gs = protocol::GroupState {
             group_id: group_id.clone(),
             my_sender_key: Some(protocol::create_group_sender_key()),
@@ -232,41 +204,38 @@ pub fn protocol_group_init(state: State<'_, DbState>, group_id: String) -> Resul
         gs.save_to_db(conn)?;
         let dist = protocol::create_group_distribution_message(&gs)?;
         Ok(dist)
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+    }).await
 }
 
 #[tauri::command]
-pub fn protocol_group_encrypt(state: State<'_, DbState>, group_id: String, plaintext: String) -> Result<Value, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+pub async fn protocol_group_encrypt(state: State<'_, DbState>, group_id: String, plaintext: String) -> Result<Value, String> {
+    state.with_conn(move |conn| {
         let mut gs = protocol::GroupState::load_from_db(conn, &group_id)?.ok_or("Group not found")?;
         let res = protocol::group_encrypt(conn, &mut gs, &plaintext)?;
         gs.save_to_db(conn)?;
         Ok(res)
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+    }).await
 }
 
 #[tauri::command]
-pub fn protocol_group_decrypt(state: State<'_, DbState>, group_id: String, sender_hash: String, msg_obj: Value) -> Result<String, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+pub async fn protocol_group_decrypt(app: tauri::AppHandle, state: State<'_, DbState>, group_id: String, sender_hash: String, msg_obj: Value) -> Result<String, String> {
+    let (res, updated) = state.with_conn(move |conn| {
         let mut gs = protocol::GroupState::load_from_db(conn, &group_id)?.ok_or("Group not found")?;
         let res = protocol::group_decrypt(&mut gs, &sender_hash, &msg_obj)?;
         gs.save_to_db(conn)?;
-        Ok(res)
-    } else {
-        Err("Vault not initialized".to_string())
+        // The sender key that decrypted it vouches for `sender_hash`.
+        let updated = protocol::apply_incoming_control(conn, &group_id, &sender_hash, &res);
+        Ok((res, updated))
+    }).await?;
+    if let Some(updated) = updated {
+        let _ = app.emit("message-updated", updated);
     }
+    Ok(res)
 }
 
 #[tauri::command]
-pub fn protocol_process_group_distribution(state: State<'_, DbState>, sender_hash: String, dist_obj: Value) -> Result<(), String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+pub async fn protocol_process_group_distribution(state: State<'_, DbState>, sender_hash: String, dist_obj: Value) -> Result<(), String> {
+    state.with_conn(move |conn| {
         let group_id = dist_obj["group_id"].as_str().ok_or("Missing group_id")?;
         let mut gs = protocol::GroupState::load_from_db(conn, group_id)?.unwrap_or_else(|| protocol::GroupState {
             group_id: group_id.to_string(),
@@ -274,103 +243,358 @@ pub fn protocol_process_group_distribution(state: State<'_, DbState>, sender_has
             member_sender_keys: std::collections::HashMap::new(),
             members: vec![]
         });
-        
+    
         let sk = protocol::SenderKey {
             key_id: dist_obj["key_id"].as_u64().ok_or("Missing key_id")? as u32,
             chain_key: dist_obj["chain_key"].as_str().ok_or("Missing chain_key")?.to_string(),
             signature_key_private: "".to_string(), 
             signature_key_public: dist_obj["signature_key_public"].as_str().ok_or("Missing signature_key_public")?.to_string(),
         };
-        
+    
         gs.member_sender_keys.insert(sender_hash, sk);
         gs.save_to_db(conn)?;
         Ok(())
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+    }).await
 }
 
 #[tauri::command]
-pub fn protocol_get_pending(state: State<'_, DbState>) -> Result<Vec<protocol::PendingMessage>, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
-        protocol::get_pending_messages(conn)
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+pub async fn protocol_get_pending(state: State<'_, DbState>) -> Result<Vec<protocol::PendingMessage>, String> {
+    state.with_conn(protocol::get_pending_messages).await
 }
 
 #[tauri::command]
-pub fn protocol_remove_pending(state: State<'_, DbState>, id: String) -> Result<(), String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
-        protocol::remove_pending_message(conn, &id)
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+pub async fn protocol_remove_pending(state: State<'_, DbState>, id: String) -> Result<(), String> {
+    state.with_conn(move |conn| protocol::remove_pending_message(conn, &id)).await
 }
 
 #[tauri::command]
-pub fn protocol_save_pending(state: State<'_, DbState>, msg: protocol::PendingMessage) -> Result<(), String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
-        protocol::save_pending_message(conn, &msg)
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+pub async fn protocol_save_pending(state: State<'_, DbState>, msg: protocol::PendingMessage) -> Result<(), String> {
+    state.with_conn(move |conn| protocol::save_pending_message(conn, &msg)).await
 }
 
+/// Exports whichever vault is unlocked, so a decoy exports as itself. The file is read on
+/// the database thread after cached sessions are written, so no write lands halfway through.
 #[tauri::command]
-pub fn protocol_export_vault(app: tauri::AppHandle) -> Result<Vec<u8>, String> {
+pub async fn protocol_export_vault(state: State<'_, DbState>) -> Result<Vec<u8>, String> {
+    let path = state.open_file()?.path();
+    state.with_conn_flushed(move |_| {
+        if !path.exists() { return Err("Vault does not exist".to_string()); }
+        std::fs::read(&path).map_err(|e| e.to_string())
+    }).await
+}
+
+/// The KDF header is needed alongside the exported bytes to reopen the vault.
+#[tauri::command]
+pub fn protocol_export_vault_header(state: State<'_, DbState>) -> Result<Option<String>, String> {
+    state.open_file()?.header()?
+        .map(|header| serde_json::to_string(&header).map_err(|e| e.to_string()))
+        .transpose()
+}
+
+/// Replaces the live vault with an exported `vault.db`, which must open with `passphrase`.
+/// The import is validated in a temporary file and the current vault is only replaced
+/// once it has passed, and restored if the new one fails to open.
+#[tauri::command]
+pub async fn protocol_import_vault(app: tauri::AppHandle, state: State<'_, DbState>, bytes: Vec<u8>, header: Option<String>, passphrase: String) -> Result<(), String> {
+    refuse_import_into_decoy(&state)?;
     let app_data_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    if !app_data_dir.exists() {
+        std::fs::create_dir_all(&app_data_dir).map_err(|e| e.to_string())?;
+    }
     let db_path = app_data_dir.join("vault.db");
-    if !db_path.exists() { return Err("Vault does not exist".to_string()); }
-    std::fs::read(db_path).map_err(|e| e.to_string())
+    let import_path = db_path.with_extension("db.import");
+
+    // Exports from before the KDF header were keyed with the passphrase directly.
+    let header = header
+        .map(|h| serde_json::from_str::<protocol::VaultHeader>(&h).map_err(|e| format!("Invalid vault header: {}", e)))
+        .transpose()?;
+    let prepared = {
+        let (import_path, passphrase) = (import_path.clone(), passphrase.clone());
+        tauri::async_runtime::spawn_blocking(move || protocol::prepare_vault_import(&bytes, header, &passphrase, &import_path))
+    };
+    let header = prepared.await.map_err(|e| e.to_string())??;
+
+    install_import(&app, &state, db_path, import_path, header, passphrase).await
 }
 
-#[tauri::command]
-pub fn protocol_import_vault(app: tauri::AppHandle, state: State<'_, DbState>, bytes: Vec<u8>) -> Result<(), String> {
-    {
-        let mut lock = state.conn.lock().unwrap();
-        *lock = None;
+/// Imports always replace `vault.db`, so one started from inside the decoy would overwrite
+/// the real vault behind it while the decoy stays open.
+fn refuse_import_into_decoy(state: &DbState) -> Result<(), String> {
+    match state.open_file() {
+        Ok(protocol::VaultFile::Decoy(_)) => Err("Imports are not available in this vault".to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Swaps a prepared import in as the live vault. If that fails the previous vault is put
+/// back on disk but left closed, so the UI is sent to the unlock screen to reopen it.
+async fn install_import(
+    app: &tauri::AppHandle,
+    state: &DbState,
+    db_path: std::path::PathBuf,
+    import_path: std::path::PathBuf,
+    header: protocol::VaultHeader,
+    passphrase: String
+) -> Result<(), String> {
+    let file = protocol::VaultFile::Main(db_path.clone());
+    let installed = state.with_slot(move |slot| {
+        *slot = None;
+        *slot = Some(protocol::replace_vault(&db_path, &import_path, &header, &passphrase)?);
+        Ok(())
+    }).await;
+    match installed {
+        Ok(()) => {
+            state.set_open_file(Some(file));
+            Ok(())
+        }
+        Err(e) => {
+            let _ = state.with_slot(|slot| {
+                *slot = None;
+                Ok(())
+            }).await;
+            state.mark_locked();
+            let _ = app.emit("vault-locked", serde_json::json!({ "reason": "import_failed" }));
+            Err(e)
+        }
     }
+}
 
+#[tauri::command]
+pub async fn protocol_export_backup(app: tauri::AppHandle, state: State<'_, DbState>, grants: State<'_, FileGrants>, token: String, backup_passphrase: String) -> Result<(), String> {
+    let path = grants.resolve(&token, FileAccess::Write)?;
+    let app_data_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    state.with_conn_flushed(move |conn| protocol::export_backup(conn, &app_data_dir.join("vault.db"), &path, &backup_passphrase)).await?;
+    grants.revoke(&token);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn protocol_inspect_backup(grants: State<'_, FileGrants>, token: String) -> Result<Value, String> {
+    let header = protocol::read_backup_header(&grants.resolve(&token, FileAccess::Read)?)?;
+    Ok(serde_json::json!({
+        "version": header.version,
+        "createdAt": header.created_at,
+        "schemaVersion": header.schema_version,
+    }))
+}
+
+/// Restores an `.entropy-backup`, re-keyed so the vault afterwards unlocks with `vault_passphrase`.
+/// The backup is fully decrypted and checked before the live vault is closed.
+#[tauri::command]
+pub async fn protocol_import_backup(app: tauri::AppHandle, state: State<'_, DbState>, grants: State<'_, FileGrants>, token: String, backup_passphrase: String, vault_passphrase: String) -> Result<(), String> {
+    refuse_import_into_decoy(&state)?;
+    let path = grants.resolve(&token, FileAccess::Read)?;
     let app_data_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
     if !app_data_dir.exists() {
         std::fs::create_dir_all(&app_data_dir).map_err(|e| e.to_string())?;
     }
     let db_path = app_data_dir.join("vault.db");
-    std::fs::write(db_path, bytes).map_err(|e| e.to_string())?;
-    
+    let import_path = db_path.with_extension("db.import");
+    let restored = {
+        let (import_path, vault_passphrase) = (import_path.clone(), vault_passphrase.clone());
+        tauri::async_runtime::spawn_blocking(move || protocol::restore_backup(&path, &import_path, &backup_passphrase, &vault_passphrase))
+    };
+    let header = restored.await.map_err(|e| e.to_string())??;
+
+    install_import(&app, &state, db_path, import_path, header, vault_passphrase).await?;
+    grants.revoke(&token);
     Ok(())
 }
 
+/// Enables scheduled backups into the picked directory, keeping the newest `keep` generations.
 #[tauri::command]
-pub fn protocol_save_vault_to_path(path: String, bytes: Vec<u8>) -> Result<(), String> {
-    std::fs::write(path, bytes).map_err(|e| e.to_string())
+pub async fn protocol_configure_auto_backup(state: State<'_, DbState>, grants: State<'_, FileGrants>, directory_token: String, interval_minutes: u64, keep: u32, backup_passphrase: String) -> Result<(), String> {
+    let directory = grants.resolve(&directory_token, FileAccess::Directory)?;
+    if !directory.is_dir() {
+        return Err("Backup directory does not exist".to_string());
+    }
+    if interval_minutes < 15 {
+        return Err("Backup interval must be at least 15 minutes".to_string());
+    }
+    if keep == 0 {
+        return Err("At least one backup generation must be kept".to_string());
+    }
+    let key = tauri::async_runtime::spawn_blocking(move || protocol::BackupKey::derive(&backup_passphrase))
+        .await
+        .map_err(|e| e.to_string())??;
+
+    let config = protocol::AutoBackupConfig { directory: directory.to_string_lossy().to_string(), interval_minutes, keep, key };
+    state.with_conn(move |conn| protocol::save_auto_backup_config(conn, Some(&config))).await?;
+    grants.revoke(&directory_token);
+    Ok(())
 }
 
 #[tauri::command]
-pub fn protocol_read_vault_from_path(path: String) -> Result<Vec<u8>, String> {
-    std::fs::read(path).map_err(|e| e.to_string())
+pub async fn protocol_disable_auto_backup(state: State<'_, DbState>) -> Result<(), String> {
+    state.with_conn(move |conn| protocol::save_auto_backup_config(conn, None)).await
 }
 
 #[tauri::command]
-pub fn protocol_save_message(state: State<'_, DbState>, peer_hash: String, msg: Value) -> Result<(), String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
-        protocol::save_decrypted_message(conn, &peer_hash, &msg)
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+pub async fn protocol_get_auto_backup_status(state: State<'_, DbState>) -> Result<Value, String> {
+    state.with_conn(move |conn| {
+        let config = protocol::load_auto_backup_config(conn)?;
+        let status = protocol::load_auto_backup_status(conn)?;
+        Ok(serde_json::json!({
+            "enabled": config.is_some(),
+            "directory": config.as_ref().map(|c| c.directory.clone()),
+            "intervalMinutes": config.as_ref().map(|c| c.interval_minutes),
+            "keep": config.as_ref().map(|c| c.keep),
+            "status": status,
+        }))
+    }).await
 }
 
 #[tauri::command]
-pub fn protocol_search_messages(state: State<'_, DbState>, query: String) -> Result<Vec<Value>, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
-        protocol::search_messages(conn, &query)
-    } else {
-        Err("Vault not initialized".to_string())
+pub async fn protocol_run_auto_backup(app: tauri::AppHandle) -> Result<Option<protocol::AutoBackupStatus>, String> {
+    tauri::async_runtime::spawn_blocking(move || run_auto_backup(&app, true))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// Runs one scheduled backup if one is configured and due (or `force` is set). Only the
+/// snapshot runs on the database thread; encryption and rotation happen after it returns.
+fn run_auto_backup(app: &tauri::AppHandle, force: bool) -> Result<Option<protocol::AutoBackupStatus>, String> {
+    let db_path = app.path().app_data_dir().map_err(|e| e.to_string())?.join("vault.db");
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let state = app.state::<DbState>();
+
+    let due = {
+        let db_path = db_path.clone();
+        state.with_conn_flushed_blocking(move |conn| {
+            let Some(config) = protocol::load_auto_backup_config(conn)? else { return Ok(None) };
+            let status = protocol::load_auto_backup_status(conn)?;
+            if !force && !status.is_due(&config, now) {
+                return Ok(None);
+            }
+            let snapshot = protocol::snapshot_vault(conn, &db_path, &config.key);
+            Ok(Some((config, status, snapshot)))
+        })?
+    };
+    let Some((config, mut status, snapshot)) = due else { return Ok(None) };
+
+    let result = snapshot.and_then(|(path, header)| protocol::write_rotated_backup(&path, &header, &config));
+    status.last_attempt = Some(now);
+    match result {
+        Ok(file) => {
+            status.last_success = Some(now);
+            status.last_error = None;
+            status.last_file = Some(file.to_string_lossy().to_string());
+        }
+        Err(e) => status.last_error = Some(e),
     }
+
+    state.with_conn_flushed_blocking(move |conn| {
+        protocol::save_auto_backup_status(conn, &status)?;
+        Ok(Some(status))
+    })
+}
+
+pub fn spawn_backup_task(app: tauri::AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let handle = app.clone();
+            let ran = tauri::async_runtime::spawn_blocking(move || run_auto_backup(&handle, false)).await;
+            if let Ok(Ok(Some(status))) = ran {
+                let _ = app.emit("backup-status", status);
+            }
+        }
+    });
+}
+
+#[tauri::command]
+pub fn protocol_save_vault_to_path(grants: State<'_, FileGrants>, token: String, bytes: Vec<u8>) -> Result<(), String> {
+    std::fs::write(grants.resolve(&token, FileAccess::Write)?, bytes).map_err(|e| e.to_string())?;
+    grants.revoke(&token);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn protocol_read_vault_from_path(grants: State<'_, FileGrants>, token: String) -> Result<Vec<u8>, String> {
+    let bytes = std::fs::read(grants.resolve(&token, FileAccess::Read)?).map_err(|e| e.to_string())?;
+    grants.revoke(&token);
+    Ok(bytes)
+}
+
+#[tauri::command]
+pub async fn protocol_save_message(state: State<'_, DbState>, peer_hash: String, msg: Value) -> Result<(), String> {
+    state.with_conn(move |conn| protocol::save_decrypted_message(conn, &peer_hash, &msg)).await
+}
+
+#[tauri::command]
+pub async fn protocol_set_disappearing_timer(state: State<'_, DbState>, peer_hash: String, seconds: Option<u64>) -> Result<(), String> {
+    state.with_conn(move |conn| protocol::set_disappearing_timer(conn, &peer_hash, seconds)).await
+}
+
+#[tauri::command]
+pub async fn protocol_mark_read(state: State<'_, DbState>, peer_hash: String, ids: Vec<String>) -> Result<(), String> {
+    state.with_conn(move |conn| protocol::mark_messages_read(conn, &peer_hash, &ids)).await
+}
+
+#[tauri::command]
+pub async fn protocol_pending_expired(state: State<'_, DbState>) -> Result<Vec<Value>, String> {
+    state.with_conn(protocol::pending_expired_messages).await
+}
+
+#[tauri::command]
+pub async fn protocol_ack_expired(state: State<'_, DbState>, ids: Vec<String>) -> Result<(), String> {
+    state.with_conn(move |conn| protocol::ack_expired_messages(conn, &ids)).await
+}
+
+pub fn spawn_message_expiry_task(app: tauri::AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0);
+
+            // Leaves the session cache alone; a locked vault just yields nothing.
+            let expired = app.state::<DbState>()
+                .with_conn(move |conn| Ok(protocol::purge_expired_messages(conn, now).unwrap_or_default()))
+                .await
+                .unwrap_or_default();
+
+            if !expired.is_empty() {
+                let _ = app.emit("messages-expired", expired);
+            }
+        }
+    });
+}
+
+#[tauri::command]
+pub async fn protocol_apply_control_message(state: State<'_, DbState>, peer_hash: String, control: Value) -> Result<Value, String> {
+    state.with_conn(move |conn| protocol::apply_local_control(conn, &peer_hash, &control)).await
+}
+
+#[tauri::command]
+pub async fn protocol_get_edit_history(state: State<'_, DbState>, message_id: String) -> Result<Vec<Value>, String> {
+    state.with_conn(move |conn| protocol::get_edit_history(conn, &message_id)).await
+}
+
+#[tauri::command]
+pub async fn protocol_get_messages(state: State<'_, DbState>, peer_hash: String, before_cursor: Option<String>, limit: Option<u32>) -> Result<Value, String> {
+    state.with_conn(move |conn| protocol::get_messages(conn, &peer_hash, before_cursor.as_deref(), limit)).await
+}
+
+#[tauri::command]
+pub async fn protocol_get_conversations(state: State<'_, DbState>) -> Result<Vec<Value>, String> {
+    state.with_conn(protocol::get_conversations).await
+}
+
+#[tauri::command]
+pub async fn protocol_search_messages(
+    state: State<'_, DbState>,
+    query: String,
+    filters: Option<protocol::MessageSearchFilters>,
+    cursor: Option<String>,
+    limit: Option<u32>
+) -> Result<Value, String> {
+    state.with_conn(move |conn| protocol::search_messages_page(conn, &query, &filters.unwrap_or_default(), cursor.as_deref(), limit)).await
 }
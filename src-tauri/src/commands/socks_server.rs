@@ -0,0 +1,107 @@
+//! Server side of the SOCKS5 handshake, for the loopback endpoint of the embedded Tor
+//! client. Only username/password authentication is offered, and only with the password
+//! handed out for this launch, so other local processes cannot borrow the client.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const SOCKS_HOST_UNREACHABLE: u8 = 4;
+const SOCKS_COMMAND_UNSUPPORTED: u8 = 7;
+const SOCKS_ADDRESS_UNSUPPORTED: u8 = 8;
+
+const METHOD_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+/// An authenticated CONNECT request. The username picks the circuit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocksRequest {
+    pub username: String,
+    pub host: String,
+    pub port: u16,
+}
+
+/// Reads a SOCKS5 greeting, authentication and CONNECT request from `sock`. A client that
+/// does not authenticate with `password`, or asks for anything but CONNECT, is refused with
+/// the matching reply and `None` is returned. On success the caller sends the final reply.
+pub async fn accept_connect<S>(sock: &mut S, password: &str) -> std::io::Result<Option<SocksRequest>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = [0u8; 2];
+    sock.read_exact(&mut head).await?;
+    if head[0] != 5 {
+        return Ok(None);
+    }
+    let mut methods = vec![0u8; head[1] as usize];
+    sock.read_exact(&mut methods).await?;
+    if !methods.contains(&METHOD_PASSWORD) {
+        sock.write_all(&[5, NO_ACCEPTABLE_METHODS]).await?;
+        return Ok(None);
+    }
+    sock.write_all(&[5, METHOD_PASSWORD]).await?;
+
+    let mut ver_len = [0u8; 2];
+    sock.read_exact(&mut ver_len).await?;
+    let mut user = vec![0u8; ver_len[1] as usize];
+    sock.read_exact(&mut user).await?;
+    let mut pass_len = [0u8; 1];
+    sock.read_exact(&mut pass_len).await?;
+    let mut pass = vec![0u8; pass_len[0] as usize];
+    sock.read_exact(&mut pass).await?;
+    if !secret_eq(&pass, password.as_bytes()) {
+        sock.write_all(&[1, 1]).await?;
+        return Ok(None);
+    }
+    sock.write_all(&[1, 0]).await?;
+
+    let mut request = [0u8; 4];
+    sock.read_exact(&mut request).await?;
+    if request[1] != 1 {
+        reply(sock, SOCKS_COMMAND_UNSUPPORTED).await?;
+        return Ok(None);
+    }
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            sock.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut len = [0u8; 1];
+            sock.read_exact(&mut len).await?;
+            let mut name = vec![0u8; len[0] as usize];
+            sock.read_exact(&mut name).await?;
+            String::from_utf8_lossy(&name).into_owned()
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            sock.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        _ => {
+            reply(sock, SOCKS_ADDRESS_UNSUPPORTED).await?;
+            return Ok(None);
+        }
+    };
+    let mut port = [0u8; 2];
+    sock.read_exact(&mut port).await?;
+
+    Ok(Some(SocksRequest {
+        username: String::from_utf8_lossy(&user).into_owned(),
+        host,
+        port: u16::from_be_bytes(port),
+    }))
+}
+
+/// Sends the reply to a CONNECT request; `0` means the stream is open.
+pub async fn reply<S>(sock: &mut S, code: u8) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    sock.write_all(&[5, code, 0, 1, 0, 0, 0, 0, 0, 0]).await
+}
+
+/// Compares without stopping at the first differing byte.
+fn secret_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
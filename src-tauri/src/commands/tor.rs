@@ -0,0 +1,105 @@
+//! In-process Tor client for the `tor` routing mode, built with the `embedded-tor` feature.
+//!
+//! Arti is exposed to the rest of the app as a loopback SOCKS5 endpoint, so the relay
+//! socket and the HTTP clients use it exactly like a system Tor daemon. As with Tor's
+//! `IsolateSOCKSAuth`, streams offering different SOCKS usernames never share a circuit.
+//! The endpoint only accepts the per-launch password from [`super::StreamIsolation`].
+
+use arti_client::config::{BoolOrAuto, TorClientConfigBuilder};
+use arti_client::{IsolationToken, StreamPrefs, TorClient};
+use futures_util::StreamExt;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tauri::Emitter;
+use tokio::net::{TcpListener, TcpStream};
+use tor_rtcompat::PreferredRuntime;
+use super::socks_server::{accept_connect, reply, SOCKS_HOST_UNREACHABLE};
+
+#[derive(Serialize, Clone)]
+struct BootstrapProgress {
+    progress: f32,
+    ready: bool,
+    message: String,
+}
+
+pub struct EmbeddedTor {
+    client: TorClient<PreferredRuntime>,
+    socks_addr: SocketAddr,
+    password: String,
+    isolation: Mutex<HashMap<String, IsolationToken>>,
+}
+
+impl EmbeddedTor {
+    /// Bootstraps a Tor client keeping its state under `data_dir`, emitting `tor-bootstrap`
+    /// progress events, then starts the loopback SOCKS endpoint guarded by `password`.
+    pub async fn start(app: &tauri::AppHandle, data_dir: &Path, password: String) -> Result<Arc<Self>, String> {
+        let config = TorClientConfigBuilder::from_directories(data_dir.join("state"), data_dir.join("cache"))
+            .build()
+            .map_err(|e| e.to_string())?;
+        let client = TorClient::builder().config(config).create_unbootstrapped().map_err(|e| e.to_string())?;
+
+        let mut events = client.bootstrap_events();
+        let progress_app = app.clone();
+        tauri::async_runtime::spawn(async move {
+            while let Some(status) = events.next().await {
+                let ready = status.ready_for_traffic();
+                let _ = progress_app.emit("tor-bootstrap", BootstrapProgress {
+                    progress: status.as_frac(),
+                    ready,
+                    message: status.to_string(),
+                });
+                if ready {
+                    break;
+                }
+            }
+        });
+        client.bootstrap().await.map_err(|e| e.to_string())?;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.map_err(|e| e.to_string())?;
+        let socks_addr = listener.local_addr().map_err(|e| e.to_string())?;
+        let tor = Arc::new(Self { client, socks_addr, password, isolation: Mutex::new(HashMap::new()) });
+
+        let serving = tor.clone();
+        tauri::async_runtime::spawn(async move {
+            while let Ok((sock, _)) = listener.accept().await {
+                let tor = serving.clone();
+                tauri::async_runtime::spawn(async move {
+                    let _ = tor.serve(sock).await;
+                });
+            }
+        });
+        Ok(tor)
+    }
+
+    /// The proxy URL to hand to `connect_network` and the HTTP commands.
+    pub fn proxy_url(&self) -> String {
+        format!("socks5h://{}", self.socks_addr)
+    }
+
+    fn isolation_token(&self, username: &str) -> IsolationToken {
+        *self.isolation.lock().unwrap().entry(username.to_string()).or_insert_with(IsolationToken::new)
+    }
+
+    /// Answers one SOCKS5 CONNECT and splices the socket onto a Tor stream.
+    async fn serve(&self, mut sock: TcpStream) -> std::io::Result<()> {
+        let Some(request) = accept_connect(&mut sock, &self.password).await? else {
+            return Ok(());
+        };
+
+        let mut prefs = StreamPrefs::new();
+        prefs.connect_to_onion_services(BoolOrAuto::Explicit(true));
+        prefs.set_isolation(self.isolation_token(&request.username));
+
+        match self.client.connect_with_prefs((request.host.as_str(), request.port), &prefs).await {
+            Ok(mut stream) => {
+                reply(&mut sock, 0).await?;
+                tokio::io::copy_bidirectional(&mut sock, &mut stream).await?;
+                Ok(())
+            }
+            Err(_) => reply(&mut sock, SOCKS_HOST_UNREACHABLE).await,
+        }
+    }
+}
@@ -1,24 +1,33 @@
 use keyring::Entry;
-use rusqlite::Connection;
-use tauri::{Manager, State};
+use tauri::{Emitter, Manager, State};
 use crate::protocol;
 use crate::app_state::DbState;
 use std::collections::HashMap;
 
+/// Stores `value` in the OS keyring and confirms it through a fresh entry, since some
+/// backends accept writes they never persist.
+fn keyring_store(key: &str, value: &str) -> bool {
+    let stored = Entry::new("Entropy", key).and_then(|entry| entry.set_password(value));
+    stored.is_ok()
+        && Entry::new("Entropy", key)
+            .and_then(|entry| entry.get_password())
+            .map(|read| read == value)
+            .unwrap_or(false)
+}
+
 #[tauri::command]
 pub fn store_secret(app: tauri::AppHandle, key: String, value: String) -> Result<(), String> {
-    if let Ok(entry) = Entry::new("Entropy", &key) {
-        let _ = entry.set_password(&value);
+    let app_data_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+
+    if keyring_store(&key, &value) {
+        // Don't leave an older copy behind in the fallback store.
+        return protocol::remove_fallback_secret(&app_data_dir, &key);
     }
 
-    let app_data_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
     if !app_data_dir.exists() {
         std::fs::create_dir_all(&app_data_dir).map_err(|e| e.to_string())?;
     }
-    let secret_path = app_data_dir.join(format!("{}.secret", key));
-    std::fs::write(secret_path, value).map_err(|e| e.to_string())?;
-    
-    Ok(())
+    protocol::store_fallback_secret(&app_data_dir, &key, &value)
 }
 
 #[tauri::command]
@@ -36,16 +45,23 @@ pub fn get_secret(app: tauri::AppHandle, key: String) -> Result<String, String>
     }
 
     let app_data_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
-    let secret_path = app_data_dir.join(format!("{}.secret", key));
-    if secret_path.exists() {
-        return std::fs::read_to_string(secret_path).map_err(|e| e.to_string());
+    if let Some(value) = protocol::load_fallback_secret(&app_data_dir, &key)? {
+        return Ok(value);
     }
 
     Err("Secret not found".to_string())
 }
 
+/// Run once at startup to get rid of plaintext `.secret` files written by older versions.
+pub fn migrate_plaintext_secrets(app: &tauri::AppHandle) -> Result<(), String> {
+    let app_data_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    protocol::migrate_plaintext_secrets(&app_data_dir, keyring_store)
+}
+
+/// Opens the vault. Returns true when the passphrase was a wiping duress passphrase, so
+/// the UI can drop its local data as well.
 #[tauri::command]
-pub fn init_vault(app: tauri::AppHandle, state: State<'_, DbState>, passphrase: String) -> Result<(), String> {
+pub async fn init_vault(app: tauri::AppHandle, state: State<'_, DbState>, passphrase: String) -> Result<bool, String> {
     let app_data_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
     
     if !app_data_dir.exists() {
@@ -55,59 +71,183 @@ pub fn init_vault(app: tauri::AppHandle, state: State<'_, DbState>, passphrase:
     let db_path = app_data_dir.join("vault.db");
 
     // Close existing connection if any to release file locks
-    {
-        let mut conn_lock = state.conn.lock().unwrap();
-        if let Some(conn) = conn_lock.as_ref() {
+    let reused = state.with_slot(|slot| {
+        if let Some(conn) = slot.as_ref() {
             // If already opened and functional, we can just return Ok
             // This handles UI reloads without needing to re-open the file
             if conn.execute("SELECT 1 FROM vault LIMIT 1;", []).is_ok() {
-                return Ok(());
+                return Ok(true);
             }
         }
-        *conn_lock = None;
+        *slot = None;
+        Ok(false)
+    }).await?;
+    if reused {
+        return Ok(false);
     }
+    let wipe_dir = app_data_dir.clone();
+
+    // Key derivation and recovery are slow, so they run off the database thread.
+    let opened = tauri::async_runtime::spawn_blocking(move || {
+        protocol::recover_interrupted_import(&db_path, &passphrase)?;
+        protocol::recover_interrupted_rekey(&db_path, &passphrase)?;
+        protocol::ensure_decoy_slot(&db_path)?;
+
+        // A passphrase that does not open the vault may still be the duress passphrase. The slot
+        // always exists, so a failed unlock costs the same whether or not a decoy was set up.
+        let (conn, file, wiped) = match protocol::unlock_vault(&db_path, &passphrase) {
+            Ok(conn) => (conn, protocol::VaultFile::Main(db_path.clone()), false),
+            Err(e) => {
+                let (conn, file) = protocol::open_decoy_vault(&db_path, &passphrase)?.ok_or(e)?;
+                // A decoy that opens as the main vault has just replaced it.
+                let wiped = matches!(file, protocol::VaultFile::Main(_));
+                (conn, file, wiped)
+            }
+        };
+        if wiped {
+            wipe_local_secrets(&wipe_dir);
+        }
+        if protocol::has_discarded_vault(&db_path) {
+            let db_path = db_path.clone();
+            std::thread::spawn(move || protocol::shred_discarded_vault(&db_path));
+        }
 
-    let conn = Connection::open(db_path).map_err(|e| e.to_string())?;
+        protocol::init_database(&conn)?;
+        Ok::<_, String>((conn, file, wiped))
+    });
+    let (conn, file, wiped) = opened.await.map_err(|e| e.to_string())??;
 
-    if let Err(e) = conn.pragma_update(None, "key", passphrase) {
-        return Err(format!("Failed to set encryption key: {}", e));
+    state.with_slot(move |slot| {
+        *slot = Some(conn);
+        Ok(())
+    }).await?;
+    state.set_open_file(Some(file));
+    state.mark_unlocked();
+    Ok(wiped)
+}
+
+/// Closes the vault until `init_vault` is called again with the passphrase.
+#[tauri::command]
+pub async fn vault_lock(app: tauri::AppHandle, state: State<'_, DbState>) -> Result<(), String> {
+    if state.lock_vault().await? {
+        let _ = app.emit("vault-locked", serde_json::json!({ "reason": "manual" }));
     }
+    Ok(())
+}
+
+/// Called by the UI on user input; background traffic does not keep the vault open.
+#[tauri::command]
+pub fn vault_report_activity(state: State<'_, DbState>) {
+    state.touch();
+}
 
-    protocol::init_database(&conn)?;
+#[tauri::command]
+pub async fn vault_get_auto_lock(state: State<'_, DbState>) -> Result<u32, String> {
+    state.with_conn(protocol::load_auto_lock_minutes).await
+}
 
-    let mut db_conn = state.conn.lock().unwrap();
-    *db_conn = Some(conn);
+#[tauri::command]
+pub async fn vault_set_auto_lock(state: State<'_, DbState>, minutes: u32) -> Result<(), String> {
+    state.with_conn(move |conn| protocol::save_auto_lock_minutes(conn, minutes)).await?;
+    state.touch();
     Ok(())
 }
 
+pub fn spawn_auto_lock_task(app: tauri::AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(15));
+        loop {
+            interval.tick().await;
+            let state = app.state::<DbState>();
+            let timeout_minutes = state.with_conn(|conn| {
+                Ok(protocol::load_auto_lock_minutes(conn).unwrap_or(protocol::DEFAULT_AUTO_LOCK_MINUTES))
+            }).await;
+            let Ok(timeout_minutes) = timeout_minutes else { continue };
+            if timeout_minutes == 0 || state.idle_for() < std::time::Duration::from_secs(timeout_minutes as u64 * 60) {
+                continue;
+            }
+            if let Ok(true) = state.lock_vault().await {
+                let _ = app.emit("vault-locked", serde_json::json!({ "reason": "idle" }));
+            }
+        }
+    });
+}
+
+#[tauri::command]
+pub async fn vault_change_passphrase(state: State<'_, DbState>, old_passphrase: String, new_passphrase: String) -> Result<(), String> {
+    let file = state.open_file()?;
+    state.with_slot(move |slot| match &file {
+        protocol::VaultFile::Main(db_path) => protocol::change_vault_passphrase(slot, db_path, &old_passphrase, &new_passphrase),
+        protocol::VaultFile::Decoy(db_path) => protocol::change_decoy_passphrase(slot, db_path, &old_passphrase, &new_passphrase),
+    }).await
+}
+
+/// Sets up a decoy vault opened by `duress_passphrase`. With `wipe_real_vault`, unlocking
+/// with it also destroys this vault and leaves the decoy as the only one. From inside the
+/// decoy this only checks the passphrase: the decoy occupies the slot it would replace.
 #[tauri::command]
-pub fn clear_vault(state: State<'_, DbState>) -> Result<(), String> {
-    let conn_lock = state.conn.lock().unwrap();
-    if let Some(conn) = conn_lock.as_ref() {
-        conn.execute("DELETE FROM vault;", []).map_err(|e| e.to_string())?;
+pub async fn vault_set_duress_passphrase(state: State<'_, DbState>, passphrase: String, duress_passphrase: String, wipe_real_vault: bool) -> Result<(), String> {
+    if duress_passphrase == passphrase {
+        return Err("Duress passphrase must differ from the vault passphrase".to_string());
     }
-    Ok(())
+    let file = state.open_file()?;
+    tauri::async_runtime::spawn_blocking(move || {
+        file.check_passphrase(&passphrase)?;
+        match &file {
+            protocol::VaultFile::Main(db_path) => protocol::create_decoy_vault(db_path, &duress_passphrase, wipe_real_vault),
+            protocol::VaultFile::Decoy(_) => Ok(()),
+        }
+    }).await.map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+pub async fn vault_clear_duress_passphrase(state: State<'_, DbState>, passphrase: String) -> Result<(), String> {
+    let file = state.open_file()?;
+    tauri::async_runtime::spawn_blocking(move || {
+        file.check_passphrase(&passphrase)?;
+        match &file {
+            protocol::VaultFile::Main(db_path) => protocol::clear_decoy_vault(db_path),
+            protocol::VaultFile::Decoy(_) => Ok(()),
+        }
+    }).await.map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+pub async fn clear_vault(state: State<'_, DbState>) -> Result<(), String> {
+    state.with_slot(|slot| {
+        if let Some(conn) = slot.as_ref() {
+            conn.execute("DELETE FROM vault;", []).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }).await
 }
 
 #[tauri::command]
-pub fn vault_save(state: State<'_, DbState>, key: String, value: String) -> Result<(), String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+pub async fn vault_save(state: State<'_, DbState>, key: String, value: String) -> Result<(), String> {
+    let cached = is_session_cache_key(&key);
+    let save = move |conn: &rusqlite::Connection| {
         conn.execute(
             "INSERT OR REPLACE INTO vault (key, value) VALUES (?1, ?2);",
             [key, value],
         )
         .map_err(|e| e.to_string())?;
         Ok(())
+    };
+    // Only keys the session cache mirrors need it flushed and dropped around the write.
+    if cached {
+        state.with_conn_invalidating(save).await
     } else {
-        Err("Vault not initialized".to_string())
+        state.with_conn(save).await
     }
 }
 
+fn is_session_cache_key(key: &str) -> bool {
+    key == "protocol_identity" || key.starts_with("session_") || key.starts_with("crossed_session_")
+}
+
 #[tauri::command]
-pub fn vault_load(state: State<'_, DbState>, key: String) -> Result<Option<String>, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+pub async fn vault_load(state: State<'_, DbState>, key: String) -> Result<Option<String>, String> {
+    state.with_conn(move |conn| {
         let mut stmt = conn
             .prepare("SELECT value FROM vault WHERE key = ?1;")
             .map_err(|e| e.to_string())?;
@@ -118,15 +258,12 @@ pub fn vault_load(state: State<'_, DbState>, key: String) -> Result<Option<Strin
         } else {
             Ok(None)
         }
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+    }).await
 }
 
 #[tauri::command]
-pub fn dump_vault(state: State<'_, DbState>) -> Result<HashMap<String, String>, String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+pub async fn dump_vault(state: State<'_, DbState>) -> Result<HashMap<String, String>, String> {
+    state.with_conn_flushed(move |conn| {
         let mut stmt = conn.prepare("SELECT key, value FROM vault;").map_err(|e| e.to_string())?;
         let rows = stmt.query_map([], |row| {
             Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
@@ -138,15 +275,12 @@ pub fn dump_vault(state: State<'_, DbState>) -> Result<HashMap<String, String>,
             data.insert(k, v);
         }
         Ok(data)
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+    }).await
 }
 
 #[tauri::command]
-pub fn restore_vault(state: State<'_, DbState>, data: HashMap<String, String>) -> Result<(), String> {
-    let lock = state.conn.lock().unwrap();
-    if let Some(conn) = lock.as_ref() {
+pub async fn restore_vault(state: State<'_, DbState>, data: HashMap<String, String>) -> Result<(), String> {
+    state.with_conn_invalidating(move |conn| {
         for (k, v) in data {
             conn.execute(
                 "INSERT OR REPLACE INTO vault (key, value) VALUES (?1, ?2);",
@@ -154,21 +288,29 @@ pub fn restore_vault(state: State<'_, DbState>, data: HashMap<String, String>) -
             ).map_err(|e| e.to_string())?;
         }
         Ok(())
-    } else {
-        Err("Vault not initialized".to_string())
-    }
+    }).await
 }
 
 #[tauri::command]
-pub fn nuclear_reset(app: tauri::AppHandle, state: State<'_, DbState>) -> Result<(), String> {
-    if let Ok(mut conn) = state.conn.lock() {
-        *conn = None;
-    }
+pub async fn nuclear_reset(app: tauri::AppHandle, state: State<'_, DbState>) -> Result<(), String> {
+    let _ = state.with_slot(|slot| {
+        *slot = None;
+        Ok(())
+    }).await;
+    state.set_open_file(None);
 
     let app_data_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
     let _ = protocol::secure_nuke_database(&app_data_dir.join("vault.db"));
-    let _ = protocol::secure_nuke_database(&app_data_dir.join("entropy_vault_salt.secret"));
-    let _ = Entry::new("Entropy", "entropy_vault_salt").map(|entry| entry.delete_credential());
+    let _ = protocol::secure_nuke_database(&protocol::vault_header_path(&app_data_dir.join("vault.db")));
+    let _ = protocol::secure_nuke_database(&protocol::decoy_slot_path(&app_data_dir.join("vault.db")));
+    wipe_local_secrets(&app_data_dir);
     
     Ok(())
 }
+
+/// Removes what lives outside the vault: the keyring salt and the fallback secret store.
+fn wipe_local_secrets(app_data_dir: &std::path::Path) {
+    let _ = protocol::secure_nuke_database(&app_data_dir.join("entropy_vault_salt.secret"));
+    let _ = protocol::secure_nuke_database(&protocol::secret_store_path(app_data_dir));
+    let _ = Entry::new("Entropy", "entropy_vault_salt").map(|entry| entry.delete_credential());
+}
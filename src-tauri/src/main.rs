@@ -6,22 +6,18 @@ mod protocol;
 #[cfg(test)]
 mod tests;
 
-use std::sync::Mutex;
 use tauri::{
     menu::{Menu, MenuItem},
     tray::{TrayIconBuilder, TrayIconEvent},
     Manager,
 };
-use app_state::{DbState, NetworkState};
+use app_state::{DbState, FileGrants, NetworkState};
 
 fn main() {
     tauri::Builder::default()
-        .manage(DbState {
-            conn: Mutex::new(None),
-        })
-        .manage(NetworkState {
-            sender: Mutex::new(None),
-        })
+        .manage(DbState::spawn())
+        .manage(NetworkState::default())
+        .manage(FileGrants::default())
         .plugin(tauri_plugin_shell::init())
         .plugin(tauri_plugin_notification::init())
         .plugin(tauri_plugin_dialog::init())
@@ -29,12 +25,21 @@ fn main() {
             commands::store_secret,
             commands::get_secret,
             commands::init_vault,
+            commands::vault_change_passphrase,
+            commands::vault_set_duress_passphrase,
+            commands::vault_clear_duress_passphrase,
+            commands::vault_lock,
+            commands::vault_report_activity,
+            commands::vault_get_auto_lock,
+            commands::vault_set_auto_lock,
             commands::vault_save,
             commands::vault_load,
             commands::dump_vault,
             commands::restore_vault,
             commands::crypto_sha256,
             commands::crypto_pbkdf2,
+            commands::crypto_attachment_key,
+            commands::crypto_derive_attachment_key,
             commands::crypto_encrypt,
             commands::crypto_decrypt,
             commands::protocol_init,
@@ -42,6 +47,7 @@ fn main() {
             commands::protocol_establish_session,
             commands::protocol_encrypt,
             commands::protocol_decrypt,
+            commands::protocol_decrypt_batch,
             commands::protocol_encrypt_media,
             commands::protocol_decrypt_media,
             commands::protocol_encrypt_media_chunk,
@@ -60,16 +66,39 @@ fn main() {
             commands::protocol_group_decrypt,
             commands::protocol_process_group_distribution,
             commands::connect_network,
+            commands::disconnect_network,
             commands::send_to_network,
             commands::get_link_preview,
+            commands::http_get,
+            commands::set_stream_isolation,
+            commands::start_embedded_tor,
             commands::protocol_save_message,
             commands::protocol_search_messages,
+            commands::protocol_set_disappearing_timer,
+            commands::protocol_mark_read,
+            commands::protocol_pending_expired,
+            commands::protocol_ack_expired,
+            commands::protocol_get_messages,
+            commands::protocol_get_conversations,
+            commands::protocol_apply_control_message,
+            commands::protocol_get_edit_history,
             commands::nuclear_reset,
             commands::crypto_mine_pow,
             commands::clear_vault,
             commands::protocol_sign,
             commands::protocol_export_vault,
+            commands::protocol_export_vault_header,
             commands::protocol_import_vault,
+            commands::protocol_export_backup,
+            commands::protocol_inspect_backup,
+            commands::protocol_import_backup,
+            commands::protocol_configure_auto_backup,
+            commands::protocol_disable_auto_backup,
+            commands::protocol_get_auto_backup_status,
+            commands::protocol_run_auto_backup,
+            commands::fs_pick_save_file,
+            commands::fs_pick_open_file,
+            commands::fs_pick_directory,
             commands::protocol_save_vault_to_path,
             commands::protocol_read_vault_from_path
         ])
@@ -103,6 +132,11 @@ fn main() {
                 })
                 .build(app)?;
 
+            let _ = commands::migrate_plaintext_secrets(app.handle());
+            commands::spawn_message_expiry_task(app.handle().clone());
+            commands::spawn_auto_lock_task(app.handle().clone());
+            commands::spawn_backup_task(app.handle().clone());
+
             Ok(())
         })
         .run(tauri::generate_context!())
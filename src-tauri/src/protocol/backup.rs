@@ -0,0 +1,367 @@
+use aes_gcm::{Aes256Gcm, Nonce, aead::{Aead, KeyInit, Payload}};
+use rand::{RngCore, thread_rng};
+use rusqlite::backup::Backup;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::messages::now_millis;
+use super::migrations::{schema_version, MIGRATIONS};
+use super::vault::{raw_sqlcipher_key, validate_vault_file, VaultHeader};
+use super::secure_nuke_database;
+
+pub const BACKUP_MAGIC: &[u8; 8] = b"ENTRBKUP";
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+const BACKUP_CHUNK_SIZE: usize = 64 * 1024;
+const MAX_HEADER_LEN: u32 = 16 * 1024;
+const TAG_LEN: usize = 16;
+
+/// Plaintext metadata at the front of an `.entropy-backup` file. It is authenticated
+/// as associated data of every chunk, so it cannot be altered without detection.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackupHeader {
+    pub version: u32,
+    pub created_at: u64,
+    pub schema_version: u32,
+    pub kdf: VaultHeader,
+    pub nonce_prefix: String,
+    pub chunk_size: u32,
+}
+
+/// Key material for one backup passphrase. A single Argon2id run yields the SQLCipher
+/// key of the snapshot and the key of the outer chunk stream.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BackupKey {
+    pub kdf: VaultHeader,
+    material: String,
+}
+
+impl BackupKey {
+    pub fn derive(passphrase: &str) -> Result<Self, String> {
+        if passphrase.is_empty() {
+            return Err("Backup passphrase must not be empty".to_string());
+        }
+        Self::from_kdf(VaultHeader::generate(), passphrase)
+    }
+
+    fn from_kdf(kdf: VaultHeader, passphrase: &str) -> Result<Self, String> {
+        let material = hex::encode(kdf.derive_bytes(passphrase, 64)?);
+        Ok(Self { kdf, material })
+    }
+
+    fn db_key(&self) -> Result<String, String> {
+        let okm = hex::decode(&self.material).map_err(|e| e.to_string())?;
+        Ok(raw_sqlcipher_key(&okm[..32]))
+    }
+
+    fn cipher(&self) -> Result<Aes256Gcm, String> {
+        let okm = hex::decode(&self.material).map_err(|e| e.to_string())?;
+        Aes256Gcm::new_from_slice(&okm[32..]).map_err(|e| format!("{:?}", e))
+    }
+}
+
+/// STREAM-style nonce: random prefix, chunk counter and a final-chunk flag, so
+/// reordered, dropped or truncated chunks fail authentication.
+fn chunk_nonce(prefix: &[u8], index: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..7].copy_from_slice(prefix);
+    nonce[7..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+fn read_header(reader: &mut impl Read) -> Result<(BackupHeader, Vec<u8>), String> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).map_err(|_| "Not an Entropy backup file".to_string())?;
+    if &magic != BACKUP_MAGIC {
+        return Err("Not an Entropy backup file".to_string());
+    }
+
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len).map_err(|_| "Backup file is truncated".to_string())?;
+    let len = u32::from_le_bytes(len);
+    if len > MAX_HEADER_LEN {
+        return Err("Backup header is corrupt".to_string());
+    }
+    let mut header_bytes = vec![0u8; len as usize];
+    reader.read_exact(&mut header_bytes).map_err(|_| "Backup file is truncated".to_string())?;
+
+    let header: BackupHeader = serde_json::from_slice(&header_bytes).map_err(|_| "Backup header is corrupt".to_string())?;
+    if header.version > BACKUP_FORMAT_VERSION {
+        return Err(format!("Backup format v{} was created by a newer version of Entropy", header.version));
+    }
+    if header.schema_version as usize > MIGRATIONS.len() {
+        return Err(format!("Backup contains vault schema v{}, which this version cannot open", header.schema_version));
+    }
+    if header.chunk_size == 0 || header.chunk_size as usize > 16 * BACKUP_CHUNK_SIZE {
+        return Err("Backup header is corrupt".to_string());
+    }
+    Ok((header, header_bytes))
+}
+
+/// Reads only the plaintext header, e.g. to show the creation date before asking for
+/// the backup passphrase.
+pub fn read_backup_header(path: &Path) -> Result<BackupHeader, String> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+    read_header(&mut reader).map(|(h, _)| h)
+}
+
+/// Writes an `.entropy-backup` of the open vault to `dest`.
+pub fn export_backup(conn: &Connection, db_path: &Path, dest: &Path, backup_passphrase: &str) -> Result<(), String> {
+    let key = BackupKey::derive(backup_passphrase)?;
+    let (snapshot_path, header) = snapshot_vault(conn, db_path, &key)?;
+    seal_snapshot(&snapshot_path, dest, &header, &key)
+}
+
+/// Copies the open vault with SQLite's online backup API into a file keyed for the
+/// backup. Only this step needs the connection; sealing can happen after it is released.
+pub fn snapshot_vault(conn: &Connection, db_path: &Path, key: &BackupKey) -> Result<(PathBuf, BackupHeader), String> {
+    let mut prefix = [0u8; 7];
+    thread_rng().fill_bytes(&mut prefix);
+    let header = BackupHeader {
+        version: BACKUP_FORMAT_VERSION,
+        created_at: now_millis(),
+        schema_version: schema_version(conn)?,
+        kdf: key.kdf.clone(),
+        nonce_prefix: hex::encode(prefix),
+        chunk_size: BACKUP_CHUNK_SIZE as u32,
+    };
+
+    let snapshot_path = db_path.with_extension("db.snapshot");
+    let _ = secure_nuke_database(&snapshot_path);
+    let result = (|| {
+        let mut dest = Connection::open(&snapshot_path).map_err(|e| e.to_string())?;
+        dest.pragma_update(None, "key", key.db_key()?).map_err(|e| e.to_string())?;
+        let backup = Backup::new(conn, &mut dest).map_err(|e| e.to_string())?;
+        backup.run_to_completion(256, std::time::Duration::ZERO, None)
+            .map_err(|e| format!("Failed to snapshot vault: {}", e))
+    })();
+    if let Err(e) = result {
+        let _ = secure_nuke_database(&snapshot_path);
+        return Err(e);
+    }
+    Ok((snapshot_path, header))
+}
+
+/// Encrypts a snapshot into `dest` and wipes the snapshot, whether or not it succeeds.
+pub fn seal_snapshot(snapshot_path: &Path, dest: &Path, header: &BackupHeader, key: &BackupKey) -> Result<(), String> {
+    let result = key.cipher().and_then(|cipher| encrypt_stream(snapshot_path, dest, header, &cipher));
+    let _ = secure_nuke_database(snapshot_path);
+    if result.is_err() {
+        let _ = std::fs::remove_file(dest);
+    }
+    result
+}
+
+fn encrypt_stream(src: &Path, dest: &Path, header: &BackupHeader, cipher: &Aes256Gcm) -> Result<(), String> {
+    let header_bytes = serde_json::to_vec(header).map_err(|e| e.to_string())?;
+    let prefix = hex::decode(&header.nonce_prefix).map_err(|e| e.to_string())?;
+    let chunk_size = header.chunk_size as usize;
+
+    let total = std::fs::metadata(src).map_err(|e| e.to_string())?.len() as usize;
+    let chunks = total.div_ceil(chunk_size).max(1);
+    let mut reader = BufReader::new(File::open(src).map_err(|e| e.to_string())?);
+    let mut writer = BufWriter::new(File::create(dest).map_err(|e| e.to_string())?);
+
+    writer.write_all(BACKUP_MAGIC).map_err(|e| e.to_string())?;
+    writer.write_all(&(header_bytes.len() as u32).to_le_bytes()).map_err(|e| e.to_string())?;
+    writer.write_all(&header_bytes).map_err(|e| e.to_string())?;
+
+    let mut buf = vec![0u8; chunk_size];
+    for index in 0..chunks {
+        let len = std::cmp::min(chunk_size, total - index * chunk_size);
+        reader.read_exact(&mut buf[..len]).map_err(|e| e.to_string())?;
+        let nonce = chunk_nonce(&prefix, index as u32, index == chunks - 1);
+        let ct = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: &buf[..len], aad: &header_bytes })
+            .map_err(|_| "Backup encryption failed".to_string())?;
+        writer.write_all(&(ct.len() as u32).to_le_bytes()).map_err(|e| e.to_string())?;
+        writer.write_all(&ct).map_err(|e| e.to_string())?;
+    }
+    writer.flush().map_err(|e| e.to_string())?;
+    writer.get_ref().sync_all().map_err(|e| e.to_string())
+}
+
+fn decrypt_stream(reader: &mut impl Read, dest: &Path, header: &BackupHeader, header_bytes: &[u8], cipher: &Aes256Gcm) -> Result<(), String> {
+    let prefix = hex::decode(&header.nonce_prefix).map_err(|_| "Backup header is corrupt".to_string())?;
+    if prefix.len() != 7 {
+        return Err("Backup header is corrupt".to_string());
+    }
+    let max_ct = header.chunk_size as usize + TAG_LEN;
+    let mut writer = BufWriter::new(File::create(dest).map_err(|e| e.to_string())?);
+
+    let mut index: u32 = 0;
+    let mut len_buf = [0u8; 4];
+    let mut ct = vec![0u8; max_ct];
+    loop {
+        reader.read_exact(&mut len_buf).map_err(|_| "Backup file is truncated".to_string())?;
+        let len = u32::from_le_bytes(len_buf) as usize;
+        if !(TAG_LEN..=max_ct).contains(&len) {
+            return Err("Backup file is corrupt".to_string());
+        }
+        reader.read_exact(&mut ct[..len]).map_err(|_| "Backup file is truncated".to_string())?;
+
+        // A short chunk can only be the last one; a full one may be either.
+        let mut last = len < max_ct;
+        let mut plain = cipher.decrypt(Nonce::from_slice(&chunk_nonce(&prefix, index, last)), Payload { msg: &ct[..len], aad: header_bytes });
+        if plain.is_err() && !last {
+            last = true;
+            plain = cipher.decrypt(Nonce::from_slice(&chunk_nonce(&prefix, index, last)), Payload { msg: &ct[..len], aad: header_bytes });
+        }
+        let plain = plain.map_err(|_| {
+            if index == 0 { "Wrong backup passphrase or corrupt backup".to_string() } else { "Backup file is corrupt".to_string() }
+        })?;
+        writer.write_all(&plain).map_err(|e| e.to_string())?;
+
+        if last {
+            break;
+        }
+        index = index.checked_add(1).ok_or("Backup file is corrupt")?;
+    }
+
+    let mut trailing = [0u8; 1];
+    if reader.read(&mut trailing).map_err(|e| e.to_string())? != 0 {
+        return Err("Backup file has trailing data".to_string());
+    }
+    writer.flush().map_err(|e| e.to_string())?;
+    writer.get_ref().sync_all().map_err(|e| e.to_string())
+}
+
+/// Decrypts `src` into a standalone vault at `dest`, keyed for `vault_passphrase` under
+/// a fresh KDF header which is returned. Nothing outside `dest` is touched, and `dest`
+/// is removed again if the backup fails any check.
+pub fn restore_backup(src: &Path, dest: &Path, backup_passphrase: &str, vault_passphrase: &str) -> Result<VaultHeader, String> {
+    let mut reader = BufReader::new(File::open(src).map_err(|e| e.to_string())?);
+    let (header, header_bytes) = read_header(&mut reader)?;
+    let key = BackupKey::from_kdf(header.kdf.clone(), backup_passphrase)?;
+    let db_key = key.db_key()?;
+
+    let result = (|| {
+        decrypt_stream(&mut reader, dest, &header, &header_bytes, &key.cipher()?)?;
+
+        let conn = validate_vault_file(dest, &db_key).map_err(|e| format!("Backup contents are not a valid vault: {}", e))?;
+        if schema_version(&conn)? != header.schema_version {
+            return Err("Backup contents do not match their header".to_string());
+        }
+
+        let vault_header = VaultHeader::generate();
+        conn.pragma_update(None, "rekey", vault_header.derive_key(vault_passphrase)?).map_err(|e| e.to_string())?;
+        Ok(vault_header)
+    })();
+    if result.is_err() {
+        let _ = secure_nuke_database(dest);
+    }
+    result
+}
+
+/// Settings for the scheduled backup task, kept in the vault. The derived backup key is
+/// stored rather than the passphrase, so runs don't repeat the Argon2id derivation.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AutoBackupConfig {
+    pub directory: String,
+    pub interval_minutes: u64,
+    pub keep: u32,
+    pub key: BackupKey,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoBackupStatus {
+    pub last_attempt: Option<u64>,
+    pub last_success: Option<u64>,
+    pub last_error: Option<String>,
+    pub last_file: Option<String>,
+}
+
+impl AutoBackupStatus {
+    pub fn is_due(&self, config: &AutoBackupConfig, now: u64) -> bool {
+        match self.last_attempt {
+            Some(t) => now.saturating_sub(t) >= config.interval_minutes * 60 * 1000,
+            None => true,
+        }
+    }
+}
+
+const AUTO_BACKUP_PREFIX: &str = "entropy-auto-";
+const AUTO_BACKUP_SUFFIX: &str = ".entropy-backup";
+
+fn load_json<T: serde::de::DeserializeOwned>(conn: &Connection, key: &str) -> Result<Option<T>, String> {
+    let mut stmt = conn.prepare("SELECT value FROM vault WHERE key = ?1;").map_err(|e| e.to_string())?;
+    let mut rows = stmt.query([key]).map_err(|e| e.to_string())?;
+    match rows.next().map_err(|e| e.to_string())? {
+        Some(row) => {
+            let json: String = row.get(0).map_err(|e| e.to_string())?;
+            serde_json::from_str(&json).map(Some).map_err(|e| e.to_string())
+        }
+        None => Ok(None),
+    }
+}
+
+fn save_json<T: Serialize>(conn: &Connection, key: &str, value: &T) -> Result<(), String> {
+    let json = serde_json::to_string(value).map_err(|e| e.to_string())?;
+    conn.execute("INSERT OR REPLACE INTO vault (key, value) VALUES (?1, ?2);", params![key, json])
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+pub fn load_auto_backup_config(conn: &Connection) -> Result<Option<AutoBackupConfig>, String> {
+    load_json(conn, "auto_backup_config")
+}
+
+pub fn save_auto_backup_config(conn: &Connection, config: Option<&AutoBackupConfig>) -> Result<(), String> {
+    match config {
+        Some(c) => save_json(conn, "auto_backup_config", c),
+        None => conn.execute("DELETE FROM vault WHERE key = 'auto_backup_config';", [])
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+    }
+}
+
+pub fn load_auto_backup_status(conn: &Connection) -> Result<AutoBackupStatus, String> {
+    Ok(load_json(conn, "auto_backup_status")?.unwrap_or_default())
+}
+
+pub fn save_auto_backup_status(conn: &Connection, status: &AutoBackupStatus) -> Result<(), String> {
+    save_json(conn, "auto_backup_status", status)
+}
+
+/// Seals a snapshot as a new generation in the backup directory and prunes all but the
+/// newest `keep`. The file only gets its final name once completely written.
+pub fn write_rotated_backup(snapshot_path: &Path, header: &BackupHeader, config: &AutoBackupConfig) -> Result<PathBuf, String> {
+    let dir = Path::new(&config.directory);
+    if !dir.is_dir() {
+        let _ = secure_nuke_database(snapshot_path);
+        return Err(format!("Backup directory {} does not exist", config.directory));
+    }
+    let name = format!("{}{:013}{}", AUTO_BACKUP_PREFIX, header.created_at, AUTO_BACKUP_SUFFIX);
+    let dest = dir.join(&name);
+    let partial = dir.join(format!("{}.partial", name));
+    seal_snapshot(snapshot_path, &partial, header, &config.key)?;
+    std::fs::rename(&partial, &dest).map_err(|e| e.to_string())?;
+
+    rotate_backups(dir, config.keep)?;
+    Ok(dest)
+}
+
+/// Deletes the oldest automatic backups beyond `keep`. Other files in the directory,
+/// including manual exports, are never touched.
+pub fn rotate_backups(dir: &Path, keep: u32) -> Result<(), String> {
+    let mut generations: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| e.to_string())?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.starts_with(AUTO_BACKUP_PREFIX) && n.ends_with(AUTO_BACKUP_SUFFIX))
+                .unwrap_or(false)
+        })
+        .collect();
+    // Zero-padded timestamps sort chronologically by name.
+    generations.sort();
+    let excess = generations.len().saturating_sub(keep.max(1) as usize);
+    for old in &generations[..excess] {
+        std::fs::remove_file(old).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use rusqlite::Connection;
+use serde_json::Value;
+
+use super::{in_transaction, ratchet_decrypt_cached, unseal_with_identity, BatchItem, BatchResult, SessionCache};
+
+enum Unwrapped {
+    Pairwise(String, Value),
+    Group(Option<String>, Value),
+}
+
+fn is_sealed(envelope: &Value) -> bool {
+    envelope["sealed"].as_bool() == Some(true) || envelope.get("ephemeral_public").is_some()
+}
+
+/// Strips sealed sender and works out who a queued envelope is from, the same way the
+/// frontend does for single messages.
+fn unwrap_item(conn: &Connection, sessions: &mut SessionCache, item: BatchItem) -> Result<Unwrapped, String> {
+    let mut sender = item.sender;
+    let mut envelope = item.envelope;
+
+    if !is_sealed(&envelope) && is_sealed(&envelope["message"]) {
+        envelope = envelope["message"].take();
+    }
+    if is_sealed(&envelope) {
+        let identity = sessions.identity(conn)?.ok_or("No identity")?;
+        let (sealed_sender, message) = unseal_with_identity(&identity, &envelope)?;
+        sender = Some(sealed_sender);
+        envelope = message;
+    }
+    if sender.is_none() {
+        sender = envelope["sender"].as_str().map(str::to_string);
+    }
+
+    if envelope["type"].as_str() == Some("group_message_v2") {
+        return Ok(Unwrapped::Group(sender, envelope));
+    }
+
+    let sender = sender.ok_or("Unknown sender")?;
+    let message = match envelope.get("message") {
+        Some(inner) if inner.get("body").is_some() || envelope.get("body").is_none() => inner.clone(),
+        _ => envelope,
+    };
+    Ok(Unwrapped::Pairwise(sender, message))
+}
+
+/// Decrypts a drained offline queue. Messages are grouped by sender and each sender's
+/// run is decrypted in order inside one transaction, with a savepoint per message so a
+/// bad one only fails its own entry. Results come back in input order.
+pub fn decrypt_batch(conn: &Connection, sessions: &mut SessionCache, items: Vec<BatchItem>) -> Result<Vec<BatchResult>, String> {
+    sessions.flush(conn)?;
+
+    let mut results = Vec::with_capacity(items.len());
+    let mut by_sender: Vec<(String, Vec<(usize, Value)>)> = Vec::new();
+    for (index, item) in items.into_iter().enumerate() {
+        let fallback_sender = item.sender.clone();
+        match unwrap_item(conn, sessions, item) {
+            Ok(Unwrapped::Pairwise(sender, message)) => {
+                results.push(BatchResult { sender: Some(sender.clone()), ..Default::default() });
+                match by_sender.iter_mut().find(|(s, _)| *s == sender) {
+                    Some((_, run)) => run.push((index, message)),
+                    None => by_sender.push((sender, vec![(index, message)])),
+                }
+            }
+            Ok(Unwrapped::Group(sender, message)) => {
+                results.push(BatchResult { sender, message: Some(message), ..Default::default() });
+            }
+            Err(e) => {
+                results.push(BatchResult { sender: fallback_sender, error: Some(e), ..Default::default() });
+            }
+        }
+    }
+
+    for (sender, run) in by_sender {
+        let outcome = in_transaction(conn, |tx| {
+            let decrypted: Vec<_> = run.iter()
+                .map(|(index, message)| (*index, ratchet_decrypt_cached(tx, sessions, &sender, message)))
+                .collect();
+            sessions.flush(tx)?;
+            Ok(decrypted)
+        });
+        match outcome {
+            Ok(decrypted) => {
+                for (index, result) in decrypted {
+                    match result {
+                        Ok(plaintext) => results[index].plaintext = Some(plaintext),
+                        Err(e) => results[index].error = Some(e),
+                    }
+                }
+            }
+            Err(e) => {
+                // The cache ran ahead of what was rolled back; reload from disk next time.
+                sessions.clear();
+                for (index, _) in &run {
+                    results[*index].error = Some(e.clone());
+                }
+            }
+        }
+    }
+    Ok(results)
+}
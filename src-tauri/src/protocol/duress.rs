@@ -0,0 +1,254 @@
+use rand::{Rng, RngCore, thread_rng};
+use rusqlite::backup::Backup;
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::secure_nuke_database;
+use super::vault::{install_vault, open_vault, open_with_key, raw_sqlcipher_key, rekey_backup_path, rekey_vault, rollback_path, vault_header_path, VaultHeader};
+
+/// Every install keeps a slot next to the vault. It holds either random filler or a decoy
+/// vault opened by the duress passphrase, and the two cannot be told apart without it:
+/// SQLCipher files carry no plaintext header and both are sized in whole pages.
+pub fn decoy_slot_path(db_path: &Path) -> PathBuf {
+    db_path.with_extension("slot")
+}
+
+fn staged_slot_path(db_path: &Path) -> PathBuf {
+    db_path.with_extension("slot.tmp")
+}
+
+/// Where the real vault is moved when a duress unlock wipes it, until it is shredded.
+fn discard_path(db_path: &Path) -> PathBuf {
+    db_path.with_extension("db.discard")
+}
+
+fn discard_header_path(db_path: &Path) -> PathBuf {
+    db_path.with_extension("header.discard")
+}
+
+const SLOT_PAGE_SIZE: usize = 4096;
+const SLOT_FILLER_PAGES: std::ops::RangeInclusive<usize> = 16..=64;
+
+fn write_slot_filler(path: &Path) -> Result<(), String> {
+    let mut filler = vec![0u8; SLOT_PAGE_SIZE * thread_rng().gen_range(SLOT_FILLER_PAGES)];
+    thread_rng().fill_bytes(&mut filler);
+    std::fs::write(path, filler).map_err(|e| e.to_string())
+}
+
+/// Makes sure the slot exists, so its presence never depends on whether a decoy was set up.
+pub fn ensure_decoy_slot(db_path: &Path) -> Result<(), String> {
+    let _ = secure_nuke_database(&staged_slot_path(db_path));
+    let slot = decoy_slot_path(db_path);
+    if !slot.exists() {
+        write_slot_filler(&slot)?;
+    }
+    Ok(())
+}
+
+/// The decoy has no header of its own: its Argon2 salt is the SQLCipher salt stored in
+/// the first 16 bytes of the file, which is indistinguishable from the filler. A decoy
+/// that replaces the real vault is moved onto a proper header as it is installed.
+fn decoy_header(salt: &[u8]) -> VaultHeader {
+    VaultHeader { salt: hex::encode(salt), ..VaultHeader::generate() }
+}
+
+fn read_slot_salt(db_path: &Path) -> Option<[u8; 16]> {
+    let mut salt = [0u8; 16];
+    std::fs::File::open(decoy_slot_path(db_path)).and_then(|mut f| f.read_exact(&mut salt)).ok()?;
+    Some(salt)
+}
+
+/// Which file the open connection came from, so commands that touch the vault on disk
+/// act on the one the user unlocked.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VaultFile {
+    /// The vault at this path, with its header file beside it.
+    Main(PathBuf),
+    /// The decoy in the slot next to the vault at this path.
+    Decoy(PathBuf),
+}
+
+impl VaultFile {
+    /// The vault's own path, which names the slot and the other files around it.
+    pub fn db_path(&self) -> &Path {
+        match self {
+            VaultFile::Main(db_path) | VaultFile::Decoy(db_path) => db_path,
+        }
+    }
+
+    /// The file holding the open vault's pages.
+    pub fn path(&self) -> PathBuf {
+        match self {
+            VaultFile::Main(db_path) => db_path.clone(),
+            VaultFile::Decoy(db_path) => decoy_slot_path(db_path),
+        }
+    }
+
+    /// The header that reopens [`Self::path`] with the passphrase.
+    pub fn header(&self) -> Result<Option<VaultHeader>, String> {
+        match self {
+            VaultFile::Main(db_path) => VaultHeader::load(&vault_header_path(db_path)),
+            VaultFile::Decoy(db_path) => read_slot_salt(db_path)
+                .map(|salt| Some(decoy_header(&salt)))
+                .ok_or_else(|| "Vault does not exist".to_string()),
+        }
+    }
+
+    pub fn check_passphrase(&self, passphrase: &str) -> Result<(), String> {
+        let opened = match self {
+            VaultFile::Main(db_path) => open_vault(db_path, passphrase).is_ok(),
+            VaultFile::Decoy(db_path) => matches!(open_decoy_slot(db_path, passphrase), Ok(Some(_))),
+        };
+        if opened { Ok(()) } else { Err("Current passphrase is incorrect".to_string()) }
+    }
+}
+
+/// Whether opening the decoy should also wipe the real vault. Nothing is stored for this;
+/// it is a bit of the derived key, chosen when the decoy is created.
+fn wipes_real_vault(key: &[u8]) -> bool {
+    let digest = Sha256::new().chain_update(key).chain_update(b"Entropy duress wipe").finalize();
+    digest[0] & 1 == 1
+}
+
+/// Creates a fresh decoy vault for `duress_passphrase` in the slot, replacing whatever
+/// was there before.
+pub fn create_decoy_vault(db_path: &Path, duress_passphrase: &str, wipe_real_vault: bool) -> Result<(), String> {
+    if duress_passphrase.is_empty() {
+        return Err("Duress passphrase must not be empty".to_string());
+    }
+
+    let (salt, key) = decoy_key(duress_passphrase, wipe_real_vault)?;
+    let staged = staged_slot_path(db_path);
+    let _ = secure_nuke_database(&staged);
+    let created = open_staged_slot(&staged, &salt, &key).and_then(|conn| super::types::init_database(&conn));
+    if let Err(e) = created {
+        let _ = secure_nuke_database(&staged);
+        return Err(format!("Failed to create decoy vault: {}", e));
+    }
+
+    let slot = decoy_slot_path(db_path);
+    let _ = secure_nuke_database(&slot);
+    std::fs::rename(&staged, &slot).map_err(|e| e.to_string())
+}
+
+/// Draws salts until the key for `passphrase` carries the requested wipe bit; two tries
+/// on average.
+fn decoy_key(passphrase: &str, wipe_real_vault: bool) -> Result<([u8; 16], Vec<u8>), String> {
+    loop {
+        let mut salt = [0u8; 16];
+        thread_rng().fill_bytes(&mut salt);
+        let key = decoy_header(&salt).derive_bytes(passphrase, 32)?;
+        if wipes_real_vault(&key) == wipe_real_vault {
+            return Ok((salt, key));
+        }
+    }
+}
+
+/// Creates the staged slot file keyed with `key`. A 48-byte raw key sets the file salt
+/// explicitly instead of letting SQLCipher pick one.
+fn open_staged_slot(staged: &Path, salt: &[u8], key: &[u8]) -> Result<Connection, String> {
+    let mut key_and_salt = key.to_vec();
+    key_and_salt.extend_from_slice(salt);
+    let conn = Connection::open(staged).map_err(|e| e.to_string())?;
+    conn.pragma_update(None, "key", raw_sqlcipher_key(&key_and_salt)).map_err(|e| e.to_string())?;
+    Ok(conn)
+}
+
+/// Re-keys the open decoy for `new_passphrase`. Its salt is its header, so the pages are
+/// copied into a slot file with a new salt and swapped in, then reopened into `slot`.
+pub fn change_decoy_passphrase(slot: &mut Option<Connection>, db_path: &Path, old_passphrase: &str, new_passphrase: &str) -> Result<(), String> {
+    let conn = slot.as_ref().ok_or_else(|| "Vault not initialized".to_string())?;
+    if new_passphrase.is_empty() {
+        return Err("New passphrase must not be empty".to_string());
+    }
+    VaultFile::Decoy(db_path.to_path_buf()).check_passphrase(old_passphrase)?;
+
+    // An open decoy never carries the wipe bit: a wiping one is installed as the vault.
+    let (salt, key) = decoy_key(new_passphrase, false)?;
+    let staged = staged_slot_path(db_path);
+    let _ = secure_nuke_database(&staged);
+    let copied = open_staged_slot(&staged, &salt, &key).and_then(|mut dest| {
+        let backup = Backup::new(conn, &mut dest).map_err(|e| e.to_string())?;
+        backup.run_to_completion(256, std::time::Duration::ZERO, None)
+            .map_err(|e| format!("Failed to change passphrase: {}", e))
+    });
+    if let Err(e) = copied {
+        let _ = secure_nuke_database(&staged);
+        return Err(e);
+    }
+
+    *slot = None;
+    let slot_path = decoy_slot_path(db_path);
+    let _ = secure_nuke_database(&slot_path);
+    std::fs::rename(&staged, &slot_path).map_err(|e| e.to_string())?;
+    *slot = Some(open_with_key(&slot_path, &raw_sqlcipher_key(&key))?);
+    Ok(())
+}
+
+/// Replaces any decoy with fresh filler.
+pub fn clear_decoy_vault(db_path: &Path) -> Result<(), String> {
+    let slot = decoy_slot_path(db_path);
+    let _ = secure_nuke_database(&slot);
+    write_slot_filler(&slot)
+}
+
+/// Opens the slot with `passphrase` without acting on the wipe bit, returning the
+/// connection with its header and raw key.
+fn open_decoy_slot(db_path: &Path, passphrase: &str) -> Result<Option<(Connection, VaultHeader, Vec<u8>)>, String> {
+    let Some(salt) = read_slot_salt(db_path) else { return Ok(None) };
+    let header = decoy_header(&salt);
+    let key = header.derive_bytes(passphrase, 32)?;
+    match open_with_key(&decoy_slot_path(db_path), &raw_sqlcipher_key(&key)) {
+        Ok(conn) => Ok(Some((conn, header, key))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Tries `passphrase` against the slot. Returns `None` when it is not the duress passphrase.
+/// If the decoy was created to wipe the real vault, the real vault is moved aside for
+/// [`shred_discarded_vault`] and the decoy is installed in its place under a fresh header,
+/// so from then on it is the only vault and opens like one. The returned [`VaultFile`]
+/// is then [`VaultFile::Main`].
+pub fn open_decoy_vault(db_path: &Path, passphrase: &str) -> Result<Option<(Connection, VaultFile)>, String> {
+    let Some((conn, header, key)) = open_decoy_slot(db_path, passphrase)? else { return Ok(None) };
+    if !wipes_real_vault(&key) {
+        return Ok(Some((conn, VaultFile::Decoy(db_path.to_path_buf()))));
+    }
+    drop(conn);
+    let slot = decoy_slot_path(db_path);
+
+    if db_path.exists() {
+        std::fs::rename(db_path, discard_path(db_path)).map_err(|e| e.to_string())?;
+    }
+    let header_path = vault_header_path(db_path);
+    if header_path.exists() {
+        std::fs::rename(&header_path, discard_header_path(db_path)).map_err(|e| e.to_string())?;
+    }
+    install_vault(db_path, &slot, &header)?;
+    write_slot_filler(&slot)?;
+    let conn = open_with_key(db_path, &raw_sqlcipher_key(&key))?;
+    // Its salt doubled as the SQLCipher file salt; give it a header like any other vault.
+    rekey_vault(&conn, db_path, passphrase)?;
+    Ok(Some((conn, VaultFile::Main(db_path.to_path_buf()))))
+}
+
+pub fn has_discarded_vault(db_path: &Path) -> bool {
+    discard_path(db_path).exists()
+}
+
+/// Shreds the real vault after a wiping duress unlock, along with any copies of it left
+/// by an interrupted rekey or import. Slow on large vaults, so callers run it off-thread.
+/// The vault itself goes last so an interrupted shred is picked up again on next unlock.
+pub fn shred_discarded_vault(db_path: &Path) {
+    for path in [
+        rekey_backup_path(db_path),
+        rollback_path(db_path),
+        db_path.with_extension("header.rollback"),
+        discard_header_path(db_path),
+        discard_path(db_path),
+    ] {
+        let _ = secure_nuke_database(&path);
+    }
+}
@@ -0,0 +1,563 @@
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use rusqlite::types::Value as SqlValue;
+use crate::protocol::types::{MessageSearchFilters, ProtocolIdentity};
+use crate::protocol::utils::{encode_b64, decode_b64};
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 200;
+
+/// Column list read by `message_json`, selected from `messages m`.
+const MESSAGE_COLUMNS: &str = "m.id, m.peer_hash, m.timestamp, m.content, m.sender_hash, m.type, m.is_mine, m.status, m.reply_to_id, m.attachment_json, m.edited_at, m.deleted_at,
+    (SELECT json_group_array(json_object('reactorHash', r.reactor_hash, 'emoji', r.emoji)) FROM message_reactions r WHERE r.message_id = m.id)";
+
+pub(crate) fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub fn set_disappearing_timer(conn: &Connection, peer_hash: &str, seconds: Option<u64>) -> Result<(), String> {
+    match seconds.filter(|s| *s > 0) {
+        Some(secs) => conn.execute(
+            "INSERT OR REPLACE INTO conversation_settings (peer_hash, disappearing_seconds) VALUES (?1, ?2);",
+            params![peer_hash, secs],
+        ),
+        None => conn.execute(
+            "DELETE FROM conversation_settings WHERE peer_hash = ?1;",
+            [peer_hash],
+        ),
+    }.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+pub fn get_disappearing_timer(conn: &Connection, peer_hash: &str) -> Result<Option<u64>, String> {
+    conn.query_row(
+        "SELECT disappearing_seconds FROM conversation_settings WHERE peer_hash = ?1;",
+        [peer_hash],
+        |r| r.get(0),
+    ).optional().map_err(|e| e.to_string())
+}
+
+pub fn save_decrypted_message(
+    conn: &Connection,
+    peer_hash: &str,
+    msg: &serde_json::Value
+) -> Result<(), String> {
+    let is_mine = msg["isMine"].as_bool().unwrap_or(false);
+    let status = msg["status"].as_str().unwrap_or("sent");
+
+    // Our own messages start their countdown when sent, incoming ones once they are read.
+    let expires_at = match get_disappearing_timer(conn, peer_hash)? {
+        Some(ttl) if is_mine || status == "read" => Some(now_millis() + ttl * 1000),
+        _ => None,
+    };
+
+    // An upsert rather than INSERT OR REPLACE keeps the rowid stable for the FTS index.
+    conn.execute(
+        "INSERT INTO messages (id, peer_hash, timestamp, content, sender_hash, type, is_mine, status, reply_to_id, attachment_json, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+         ON CONFLICT(id) DO UPDATE SET
+            peer_hash = excluded.peer_hash,
+            timestamp = excluded.timestamp,
+            content = CASE WHEN messages.edited_at IS NULL AND messages.deleted_at IS NULL THEN excluded.content ELSE messages.content END,
+            sender_hash = excluded.sender_hash,
+            type = excluded.type,
+            is_mine = excluded.is_mine,
+            status = excluded.status,
+            reply_to_id = excluded.reply_to_id,
+            attachment_json = CASE WHEN messages.deleted_at IS NULL THEN excluded.attachment_json ELSE NULL END,
+            expires_at = COALESCE(messages.expires_at, excluded.expires_at)",
+        params![
+            msg["id"].as_str().ok_or("Missing id")?,
+            peer_hash,
+            msg["timestamp"].as_u64().unwrap_or(0),
+            msg["content"].as_str().unwrap_or(""),
+            msg["senderHash"].as_str().unwrap_or(""),
+            msg["type"].as_str().unwrap_or("text"),
+            if is_mine { 1 } else { 0 },
+            status,
+            msg["replyTo"]["id"].as_str(),
+            msg["attachment"].as_object().map(|_| serde_json::to_string(&msg["attachment"]).unwrap()),
+            expires_at
+        ]
+    ).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+pub fn mark_messages_read(conn: &Connection, peer_hash: &str, ids: &[String]) -> Result<(), String> {
+    let now = now_millis();
+    for id in ids {
+        conn.execute(
+            "UPDATE messages SET status = 'read',
+                expires_at = COALESCE(expires_at, (SELECT ?3 + disappearing_seconds * 1000 FROM conversation_settings WHERE peer_hash = ?2))
+             WHERE id = ?1 AND peer_hash = ?2;",
+            params![id, peer_hash, now],
+        ).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Deletes every message whose timer ran out at `now` (unix millis). Rows are removed with
+/// `secure_delete` on, which also shreds the media keys held in `attachment_json`; the
+/// returned list lets the UI drop its copies of the same messages and attachment blobs.
+/// Each one also stays in `expired_messages` until [`ack_expired_messages`], so a UI that
+/// was not listening can still pick it up from [`pending_expired_messages`].
+pub fn purge_expired_messages(conn: &Connection, now: u64) -> Result<Vec<serde_json::Value>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT id, peer_hash, attachment_json FROM messages WHERE expires_at IS NOT NULL AND expires_at <= ?1"
+    ).map_err(|e| e.to_string())?;
+
+    let rows = stmt.query_map([now], |row| {
+        let id: String = row.get(0)?;
+        let peer_hash: String = row.get(1)?;
+        let attachment_json: Option<String> = row.get(2)?;
+        let attachment: Option<serde_json::Value> = attachment_json.and_then(|s| serde_json::from_str(&s).ok());
+
+        Ok(serde_json::json!({
+            "id": id,
+            "peerHash": peer_hash,
+            "attachment": attachment
+        }))
+    }).map_err(|e| e.to_string())?;
+
+    let mut expired = Vec::new();
+    for row in rows {
+        expired.push(row.map_err(|e| e.to_string())?);
+    }
+
+    if !expired.is_empty() {
+        conn.execute(
+            "INSERT OR REPLACE INTO expired_messages (id, peer_hash, has_attachment, expired_at)
+             SELECT id, peer_hash, attachment_json IS NOT NULL, ?1 FROM messages WHERE expires_at IS NOT NULL AND expires_at <= ?1",
+            [now],
+        ).map_err(|e| e.to_string())?;
+        for table in ["message_edits", "message_reactions"] {
+            conn.execute(
+                &format!("DELETE FROM {} WHERE message_id IN (SELECT id FROM messages WHERE expires_at IS NOT NULL AND expires_at <= ?1)", table),
+                [now],
+            ).map_err(|e| e.to_string())?;
+        }
+        conn.execute(
+            "DELETE FROM messages WHERE expires_at IS NOT NULL AND expires_at <= ?1",
+            [now],
+        ).map_err(|e| e.to_string())?;
+    }
+    Ok(expired)
+}
+
+/// Expired messages the UI has not acknowledged yet, oldest first.
+pub fn pending_expired_messages(conn: &Connection) -> Result<Vec<serde_json::Value>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT id, peer_hash, has_attachment FROM expired_messages ORDER BY expired_at, id"
+    ).map_err(|e| e.to_string())?;
+    let rows = stmt.query_map([], |row| {
+        Ok(serde_json::json!({
+            "id": row.get::<_, String>(0)?,
+            "peerHash": row.get::<_, String>(1)?,
+            "hasAttachment": row.get::<_, bool>(2)?
+        }))
+    }).map_err(|e| e.to_string())?;
+    rows.collect::<rusqlite::Result<Vec<_>>>().map_err(|e| e.to_string())
+}
+
+/// Drops expired messages whose local copies and attachment blobs the UI has wiped.
+pub fn ack_expired_messages(conn: &Connection, ids: &[String]) -> Result<(), String> {
+    let mut stmt = conn.prepare("DELETE FROM expired_messages WHERE id = ?1").map_err(|e| e.to_string())?;
+    for id in ids {
+        stmt.execute([id]).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn message_json(row: &Row, base: usize) -> rusqlite::Result<serde_json::Value> {
+    let id: String = row.get(base)?;
+    let peer_hash: String = row.get(base + 1)?;
+    let timestamp: u64 = row.get(base + 2)?;
+    let content: String = row.get(base + 3)?;
+    let sender_hash: String = row.get(base + 4)?;
+    let msg_type: String = row.get(base + 5)?;
+    let is_mine: bool = row.get::<_, i32>(base + 6)? == 1;
+    let status: String = row.get(base + 7)?;
+    let reply_to_id: Option<String> = row.get(base + 8)?;
+    let attachment_json: Option<String> = row.get(base + 9)?;
+    let edited_at: Option<u64> = row.get(base + 10)?;
+    let deleted_at: Option<u64> = row.get(base + 11)?;
+    let reactions_json: Option<String> = row.get(base + 12)?;
+
+    let attachment: Option<serde_json::Value> = attachment_json.and_then(|s| serde_json::from_str(&s).ok());
+    let reactions: serde_json::Value = reactions_json
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_else(|| serde_json::json!([]));
+
+    Ok(serde_json::json!({
+        "id": id,
+        "peerHash": peer_hash,
+        "timestamp": timestamp,
+        "content": content,
+        "senderHash": sender_hash,
+        "type": msg_type,
+        "isMine": is_mine,
+        "status": status,
+        "replyTo": reply_to_id.map(|id| serde_json::json!({ "id": id })),
+        "attachment": attachment,
+        "editedAt": edited_at,
+        "isDeleted": deleted_at.is_some(),
+        "reactions": reactions
+    }))
+}
+
+/// Applies an `edit`, `delete` or `react` control message from `sender_hash` to a message
+/// in the conversation with `peer_hash`. Edits and deletes are only accepted from whoever
+/// sent the original; reactions from any participant. Edits older than the current revision
+/// are ignored. Returns the message in its new state.
+pub fn apply_control_message(
+    conn: &Connection,
+    peer_hash: &str,
+    sender_hash: &str,
+    control: &serde_json::Value
+) -> Result<serde_json::Value, String> {
+    let target_id = control["targetId"].as_str().ok_or("Missing targetId")?;
+    let timestamp = control["timestamp"].as_u64().unwrap_or_else(now_millis);
+
+    let (original_sender, current_content, sent_at, edited_at, deleted_at): (String, String, u64, Option<u64>, Option<u64>) = conn.query_row(
+        "SELECT sender_hash, content, timestamp, edited_at, deleted_at FROM messages WHERE id = ?1 AND peer_hash = ?2;",
+        params![target_id, peer_hash],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?)),
+    ).optional().map_err(|e| e.to_string())?.ok_or("Target message not found")?;
+
+    if deleted_at.is_some() {
+        return Err("Target message was deleted".to_string());
+    }
+
+    match control["type"].as_str().ok_or("Missing control type")? {
+        "edit" => {
+            if original_sender != sender_hash {
+                return Err("Only the original sender can edit a message".to_string());
+            }
+            let new_content = control["content"].as_str().ok_or("Missing content")?;
+            // A late edit that a newer one already superseded is dropped, history included.
+            if timestamp > edited_at.unwrap_or(sent_at) {
+                conn.execute(
+                    "INSERT INTO message_edits (message_id, content, edited_at) VALUES (?1, ?2, ?3);",
+                    params![target_id, current_content, edited_at],
+                ).map_err(|e| e.to_string())?;
+                conn.execute(
+                    "UPDATE messages SET content = ?2, edited_at = ?3 WHERE id = ?1;",
+                    params![target_id, new_content, timestamp],
+                ).map_err(|e| e.to_string())?;
+            }
+        }
+        "delete" => {
+            if original_sender != sender_hash {
+                return Err("Only the original sender can delete a message for everyone".to_string());
+            }
+            conn.execute("DELETE FROM message_edits WHERE message_id = ?1;", [target_id]).map_err(|e| e.to_string())?;
+            conn.execute("DELETE FROM message_reactions WHERE message_id = ?1;", [target_id]).map_err(|e| e.to_string())?;
+            conn.execute(
+                "UPDATE messages SET content = '', attachment_json = NULL, deleted_at = ?2 WHERE id = ?1;",
+                params![target_id, timestamp],
+            ).map_err(|e| e.to_string())?;
+        }
+        "react" => {
+            if !is_participant(conn, peer_hash, sender_hash)? {
+                return Err("Reaction from outside the conversation".to_string());
+            }
+            match control["emoji"].as_str().filter(|e| !e.is_empty()) {
+                Some(emoji) => conn.execute(
+                    "INSERT OR REPLACE INTO message_reactions (message_id, reactor_hash, emoji, timestamp) VALUES (?1, ?2, ?3, ?4);",
+                    params![target_id, sender_hash, emoji, timestamp],
+                ),
+                None => conn.execute(
+                    "DELETE FROM message_reactions WHERE message_id = ?1 AND reactor_hash = ?2;",
+                    params![target_id, sender_hash],
+                ),
+            }.map_err(|e| e.to_string())?;
+        }
+        other => return Err(format!("Unknown control type: {}", other)),
+    }
+
+    conn.query_row(
+        &format!("SELECT {} FROM messages m WHERE m.id = ?1;", MESSAGE_COLUMNS),
+        [target_id],
+        |row| message_json(row, 0),
+    ).map_err(|e| e.to_string())
+}
+
+/// Applies a control message from the local user, e.g. our own edit before it is sent.
+pub fn apply_local_control(conn: &Connection, peer_hash: &str, control: &serde_json::Value) -> Result<serde_json::Value, String> {
+    let identity = ProtocolIdentity::load_from_db(conn)?.ok_or("No identity")?;
+    apply_control_message(conn, peer_hash, &identity.identity_hash()?, control)
+}
+
+/// Applies a decrypted `plaintext` if it is a control message. `sender_hash` must be the
+/// peer whose session or sender key decrypted it, never a claim from the payload. Returns
+/// the updated message, or `None` for ordinary messages and controls that do not apply.
+pub fn apply_incoming_control(conn: &Connection, peer_hash: &str, sender_hash: &str, plaintext: &str) -> Option<serde_json::Value> {
+    let control: serde_json::Value = serde_json::from_str(plaintext).ok()?;
+    if !matches!(control["type"].as_str(), Some("edit" | "delete" | "react")) {
+        return None;
+    }
+    apply_control_message(conn, peer_hash, sender_hash, &control).ok()
+}
+
+/// Us, the peer itself, or anyone who has sent a message in the conversation (group members).
+fn is_participant(conn: &Connection, peer_hash: &str, sender_hash: &str) -> Result<bool, String> {
+    if sender_hash == peer_hash {
+        return Ok(true);
+    }
+    if let Some(identity) = ProtocolIdentity::load_from_db(conn)? {
+        if identity.identity_hash()? == sender_hash {
+            return Ok(true);
+        }
+    }
+    conn.prepare("SELECT 1 FROM messages WHERE peer_hash = ?1 AND sender_hash = ?2 LIMIT 1;")
+        .and_then(|mut stmt| stmt.exists([peer_hash, sender_hash]))
+        .map_err(|e| e.to_string())
+}
+
+/// Earlier versions of an edited message, oldest first. The original has no `editedAt`.
+pub fn get_edit_history(conn: &Connection, message_id: &str) -> Result<Vec<serde_json::Value>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT content, edited_at FROM message_edits WHERE message_id = ?1 ORDER BY edited_at IS NOT NULL, edited_at;"
+    ).map_err(|e| e.to_string())?;
+    let rows = stmt.query_map([message_id], |row| {
+        let content: String = row.get(0)?;
+        let edited_at: Option<u64> = row.get(1)?;
+        Ok(serde_json::json!({ "content": content, "editedAt": edited_at }))
+    }).map_err(|e| e.to_string())?;
+
+    let mut history = Vec::new();
+    for row in rows {
+        history.push(row.map_err(|e| e.to_string())?);
+    }
+    Ok(history)
+}
+
+/// One page of a conversation in chronological order. `nextCursor` points at the oldest
+/// message returned and fetches the page before it; it is absent once history runs out.
+pub fn get_messages(
+    conn: &Connection,
+    peer_hash: &str,
+    before_cursor: Option<&str>,
+    limit: Option<u32>
+) -> Result<serde_json::Value, String> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let (before_ts, before_rowid) = match before_cursor {
+        Some(c) => {
+            let (key, rowid) = decode_cursor(c)?;
+            (key.parse::<i64>().map_err(|_| "Invalid cursor")?, rowid)
+        }
+        None => (i64::MAX, i64::MAX),
+    };
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT m.rowid, {}
+         FROM messages m
+         WHERE m.peer_hash = ?1 AND (m.timestamp < ?2 OR (m.timestamp = ?2 AND m.rowid < ?3))
+         ORDER BY m.timestamp DESC, m.rowid DESC
+         LIMIT ?4",
+        MESSAGE_COLUMNS
+    )).map_err(|e| e.to_string())?;
+
+    let rows = stmt.query_map(params![peer_hash, before_ts, before_rowid, limit + 1], |row| {
+        let rowid: i64 = row.get(0)?;
+        let msg = message_json(row, 1)?;
+        Ok((rowid, msg))
+    }).map_err(|e| e.to_string())?;
+
+    let mut page = Vec::new();
+    for row in rows {
+        page.push(row.map_err(|e| e.to_string())?);
+    }
+
+    let next_cursor = if page.len() > limit as usize {
+        page.truncate(limit as usize);
+        page.last().map(|(rowid, msg)| encode_cursor(&msg["timestamp"].to_string(), *rowid))
+    } else {
+        None
+    };
+    page.reverse();
+
+    Ok(serde_json::json!({
+        "messages": page.into_iter().map(|(_, msg)| msg).collect::<Vec<_>>(),
+        "nextCursor": next_cursor
+    }))
+}
+
+/// Every peer with stored history, most recently active first.
+pub fn get_conversations(conn: &Connection) -> Result<Vec<serde_json::Value>, String> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT c.unread, {}
+         FROM (
+            SELECT p.peer_hash,
+                (SELECT rowid FROM messages WHERE peer_hash = p.peer_hash ORDER BY timestamp DESC, rowid DESC LIMIT 1) AS last_rowid,
+                (SELECT COUNT(*) FROM messages WHERE peer_hash = p.peer_hash AND is_mine = 0 AND status != 'read') AS unread
+            FROM (SELECT DISTINCT peer_hash FROM messages) p
+         ) c
+         JOIN messages m ON m.rowid = c.last_rowid
+         ORDER BY m.timestamp DESC",
+        MESSAGE_COLUMNS
+    )).map_err(|e| e.to_string())?;
+
+    let rows = stmt.query_map([], |row| {
+        let unread: u32 = row.get(0)?;
+        let last = message_json(row, 1)?;
+        Ok(serde_json::json!({
+            "peerHash": last["peerHash"],
+            "timestamp": last["timestamp"],
+            "unreadCount": unread,
+            "lastMessage": last
+        }))
+    }).map_err(|e| e.to_string())?;
+
+    let mut conversations = Vec::new();
+    for row in rows {
+        conversations.push(row.map_err(|e| e.to_string())?);
+    }
+    Ok(conversations)
+}
+
+/// Turns free text into an FTS5 query where every word is matched as a prefix.
+fn fts_query(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|t| t.replace('"', ""))
+        .filter(|t| t.chars().any(|c| c.is_alphanumeric()))
+        .map(|t| format!("\"{}\"*", t))
+        .collect();
+    if terms.is_empty() { None } else { Some(terms.join(" ")) }
+}
+
+fn encode_cursor(sort_key: &str, rowid: i64) -> String {
+    encode_b64(format!("{}:{}", sort_key, rowid).as_bytes())
+}
+
+fn decode_cursor(cursor: &str) -> Result<(String, i64), String> {
+    let raw = String::from_utf8(decode_b64(cursor)?).map_err(|_| "Invalid cursor")?;
+    let (key, rowid) = raw.rsplit_once(':').ok_or("Invalid cursor")?;
+    Ok((key.to_string(), rowid.parse().map_err(|_| "Invalid cursor")?))
+}
+
+pub fn search_messages(
+    conn: &Connection,
+    query: &str
+) -> Result<Vec<serde_json::Value>, String> {
+    let page = search_messages_page(conn, query, &MessageSearchFilters::default(), None, None)?;
+    match page["results"].as_array() {
+        Some(results) => Ok(results.clone()),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Ranked full-text search over the vault. Matches are highlighted in `snippet` with
+/// `\u{2}`/`\u{3}` around each hit so the UI never has to parse markup. Without any
+/// search words the filters alone are applied, newest first. Pass `nextCursor` from a
+/// page back in to continue after it.
+pub fn search_messages_page(
+    conn: &Connection,
+    query: &str,
+    filters: &MessageSearchFilters,
+    cursor: Option<&str>,
+    limit: Option<u32>
+) -> Result<serde_json::Value, String> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let fts = fts_query(query);
+
+    let mut sql = match fts {
+        Some(_) => format!(
+            "SELECT m.rowid, bm25(messages_fts), snippet(messages_fts, 0, char(2), char(3), '…', 12), {}
+             FROM messages_fts JOIN messages m ON m.rowid = messages_fts.rowid
+             WHERE messages_fts MATCH ?",
+            MESSAGE_COLUMNS
+        ),
+        None => format!(
+            "SELECT m.rowid, m.timestamp, NULL, {}
+             FROM messages m
+             WHERE 1 = 1",
+            MESSAGE_COLUMNS
+        ),
+    };
+    let mut args: Vec<SqlValue> = Vec::new();
+    if let Some(q) = &fts {
+        args.push(SqlValue::Text(q.clone()));
+    }
+
+    if let Some(peer) = &filters.peer_hash {
+        sql.push_str(" AND m.peer_hash = ?");
+        args.push(SqlValue::Text(peer.clone()));
+    }
+    if let Some(sender) = &filters.sender_hash {
+        sql.push_str(" AND m.sender_hash = ?");
+        args.push(SqlValue::Text(sender.clone()));
+    }
+    if let Some(since) = filters.since {
+        sql.push_str(" AND m.timestamp >= ?");
+        args.push(SqlValue::Integer(since as i64));
+    }
+    if let Some(until) = filters.until {
+        sql.push_str(" AND m.timestamp <= ?");
+        args.push(SqlValue::Integer(until as i64));
+    }
+    if let Some(msg_type) = &filters.msg_type {
+        sql.push_str(" AND m.type = ?");
+        args.push(SqlValue::Text(msg_type.clone()));
+    }
+    match filters.has_attachment {
+        Some(true) => sql.push_str(" AND m.attachment_json IS NOT NULL"),
+        Some(false) => sql.push_str(" AND m.attachment_json IS NULL"),
+        None => {}
+    }
+
+    if let Some(c) = cursor {
+        let (key, rowid) = decode_cursor(c)?;
+        if fts.is_some() {
+            let score: f64 = key.parse().map_err(|_| "Invalid cursor")?;
+            sql.push_str(" AND (bm25(messages_fts) > ? OR (bm25(messages_fts) = ? AND m.rowid > ?))");
+            args.extend([SqlValue::Real(score), SqlValue::Real(score), SqlValue::Integer(rowid)]);
+        } else {
+            let ts: i64 = key.parse().map_err(|_| "Invalid cursor")?;
+            sql.push_str(" AND (m.timestamp < ? OR (m.timestamp = ? AND m.rowid < ?))");
+            args.extend([SqlValue::Integer(ts), SqlValue::Integer(ts), SqlValue::Integer(rowid)]);
+        }
+    }
+
+    sql.push_str(match fts {
+        Some(_) => " ORDER BY bm25(messages_fts), m.rowid LIMIT ?",
+        None => " ORDER BY m.timestamp DESC, m.rowid DESC LIMIT ?",
+    });
+    // One extra row tells us whether another page exists.
+    args.push(SqlValue::Integer(limit as i64 + 1));
+
+    let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
+    let rows = stmt.query_map(params_from_iter(args), |row| {
+        let rowid: i64 = row.get(0)?;
+        let sort_key = match row.get_ref(1)? {
+            rusqlite::types::ValueRef::Real(f) => f.to_string(),
+            other => other.as_i64().map(|i| i.to_string()).unwrap_or_default(),
+        };
+        let snippet: Option<String> = row.get(2)?;
+        let mut msg = message_json(row, 3)?;
+        if let Some(snippet) = snippet {
+            msg["snippet"] = serde_json::Value::String(snippet);
+        }
+        Ok((rowid, sort_key, msg))
+    }).map_err(|e| e.to_string())?;
+
+    let mut results = Vec::new();
+    for row in rows {
+        results.push(row.map_err(|e| e.to_string())?);
+    }
+
+    let next_cursor = if results.len() > limit as usize {
+        results.truncate(limit as usize);
+        results.last().map(|(rowid, key, _)| encode_cursor(key, *rowid))
+    } else {
+        None
+    };
+
+    Ok(serde_json::json!({
+        "results": results.into_iter().map(|(_, _, msg)| msg).collect::<Vec<_>>(),
+        "nextCursor": next_cursor
+    }))
+}
@@ -0,0 +1,210 @@
+use rusqlite::{Connection, Transaction};
+
+pub(crate) type Migration = fn(&Transaction) -> rusqlite::Result<()>;
+
+/// Schema history of the vault. Entry `i` upgrades a vault from `user_version` `i` to
+/// `i + 1`; append new steps, never edit or reorder shipped ones.
+pub(crate) const MIGRATIONS: &[Migration] = &[
+    initial_schema,
+    disappearing_messages,
+    message_search_index,
+    conversation_history_indexes,
+    message_edits_and_reactions,
+    expired_message_queue,
+];
+
+pub fn schema_version(conn: &Connection) -> Result<u32, String> {
+    conn.pragma_query_value(None, "user_version", |r| r.get(0)).map_err(|e| e.to_string())
+}
+
+pub fn run_migrations(conn: &Connection) -> Result<(), String> {
+    apply_migrations(conn, MIGRATIONS)
+}
+
+/// Each step runs in its own transaction together with its `user_version` bump, so a
+/// failure leaves the vault at the last version that fully applied.
+pub(crate) fn apply_migrations(conn: &Connection, migrations: &[Migration]) -> Result<(), String> {
+    let current = schema_version(conn)? as usize;
+    if current > migrations.len() {
+        return Err(format!("Vault schema v{} is newer than this build supports (v{})", current, migrations.len()));
+    }
+
+    for (idx, migration) in migrations.iter().enumerate().skip(current) {
+        let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+        migration(&tx).map_err(|e| format!("Migration to v{} failed: {}", idx + 1, e))?;
+        tx.pragma_update(None, "user_version", (idx + 1) as u32).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn has_column(tx: &Transaction, table: &str, column: &str) -> rusqlite::Result<bool> {
+    tx.prepare(&format!("SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1;", table))?
+        .exists([column])
+}
+
+fn add_column(tx: &Transaction, table: &str, column: &str, decl: &str) -> rusqlite::Result<()> {
+    if !has_column(tx, table, column)? {
+        tx.execute(&format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, decl), [])?;
+    }
+    Ok(())
+}
+
+/// The schema every vault had before versioning; `IF NOT EXISTS` lets unversioned vaults pass through.
+fn initial_schema(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS vault (key TEXT PRIMARY KEY, value TEXT);",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS pending_messages (id TEXT PRIMARY KEY, recipient_hash TEXT, body TEXT, timestamp INTEGER, retries INTEGER);",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS groups (group_id TEXT PRIMARY KEY, state TEXT);",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS messages (
+            id TEXT PRIMARY KEY,
+            peer_hash TEXT,
+            timestamp INTEGER,
+            content TEXT,
+            sender_hash TEXT,
+            type TEXT,
+            is_mine INTEGER,
+            status TEXT,
+            reply_to_id TEXT,
+            attachment_json TEXT
+        );",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS idx_messages_peer ON messages(peer_hash);",
+        [],
+    )?;
+    Ok(())
+}
+
+fn disappearing_messages(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column(tx, "messages", "expires_at", "INTEGER")?;
+
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS idx_messages_expires ON messages(expires_at) WHERE expires_at IS NOT NULL;",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS conversation_settings (peer_hash TEXT PRIMARY KEY, disappearing_seconds INTEGER);",
+        [],
+    )?;
+    Ok(())
+}
+
+fn message_search_index(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+            content,
+            content = 'messages',
+            content_rowid = 'rowid',
+            tokenize = 'unicode61 remove_diacritics 2'
+        );",
+        [],
+    )?;
+    tx.execute(
+        "INSERT INTO messages_fts(messages_fts, rank) VALUES ('secure-delete', 1);",
+        [],
+    )?;
+    tx.execute(
+        "INSERT INTO messages_fts(messages_fts) VALUES ('rebuild');",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+            INSERT INTO messages_fts(rowid, content) VALUES (new.rowid, new.content);
+        END;",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+            INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.rowid, old.content);
+        END;",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+            INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.rowid, old.content);
+            INSERT INTO messages_fts(rowid, content) VALUES (new.rowid, new.content);
+        END;",
+        [],
+    )?;
+    Ok(())
+}
+
+fn conversation_history_indexes(tx: &Transaction) -> rusqlite::Result<()> {
+    // Superseded by idx_messages_peer_time, which serves both lookups.
+    tx.execute("DROP INDEX IF EXISTS idx_messages_peer;", [])?;
+
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS idx_messages_peer_time ON messages(peer_hash, timestamp);",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS idx_messages_unread ON messages(peer_hash) WHERE is_mine = 0 AND status != 'read';",
+        [],
+    )?;
+    Ok(())
+}
+
+fn message_edits_and_reactions(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column(tx, "messages", "edited_at", "INTEGER")?;
+    add_column(tx, "messages", "deleted_at", "INTEGER")?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS message_edits (
+            message_id TEXT,
+            content TEXT,
+            edited_at INTEGER
+        );",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS idx_message_edits_message ON message_edits(message_id, edited_at);",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS message_reactions (
+            message_id TEXT,
+            reactor_hash TEXT,
+            emoji TEXT,
+            timestamp INTEGER,
+            PRIMARY KEY (message_id, reactor_hash)
+        );",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Expired messages whose attachment blobs the UI has not confirmed wiping yet.
+fn expired_message_queue(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS expired_messages (
+            id TEXT PRIMARY KEY,
+            peer_hash TEXT,
+            has_attachment INTEGER,
+            expired_at INTEGER
+        );",
+        [],
+    )?;
+    Ok(())
+}
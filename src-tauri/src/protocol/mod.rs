@@ -3,12 +3,28 @@ pub mod crypto;
 pub mod groups;
 pub mod media;
 pub mod utils;
+pub mod messages;
+pub mod migrations;
+pub mod vault;
+pub mod backup;
+pub mod secret_store;
+pub mod duress;
+pub mod session_cache;
+pub mod batch;
 
 pub use types::*;
 pub use crypto::*;
 pub use groups::*;
 pub use media::*;
 pub use utils::*;
+pub use messages::*;
+pub use migrations::*;
+pub use vault::*;
+pub use backup::*;
+pub use secret_store::*;
+pub use duress::*;
+pub use session_cache::*;
+pub use batch::*;
 
 use rusqlite::{params, Connection};
 use std::collections::HashMap;
@@ -22,6 +38,9 @@ use pqcrypto_kyber::kyber1024;
 use pqcrypto_traits::kem::{PublicKey as PQPubKey, SecretKey as PQSecretKey};
 use pqcrypto_traits::kem::{Ciphertext, SharedSecret};
 
+/// How long the losing session of a simultaneous initiation keeps decrypting stragglers.
+pub const CROSSED_SESSION_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+
 pub fn establish_outbound_session(
     conn: &Connection,
     remote_hash: &str,
@@ -114,6 +133,7 @@ pub fn establish_outbound_session(
             combined.extend_from_slice(pq_ss2.as_bytes());
             Some(encode_b64(&combined))
         },
+        crossed_until: None,
     };
 
     state.save_to_db(conn, remote_hash)?;
@@ -141,17 +161,62 @@ fn skip_message_keys(state: &mut SessionState, target_n: u32) -> Result<(), Stri
     Ok(())
 }
 
+/// Runs `f` inside one transaction that is committed only if it returns `Ok`, so a
+/// failed encrypt or decrypt leaves the stored sessions exactly as they were. Inside an
+/// open transaction, such as a batch, it uses a savepoint instead.
+fn in_transaction<T>(
+    conn: &Connection,
+    f: impl FnOnce(&Connection) -> Result<T, String>
+) -> Result<T, String> {
+    if !conn.is_autocommit() {
+        conn.execute_batch("SAVEPOINT ratchet_op;").map_err(|e| e.to_string())?;
+        return match f(conn) {
+            Ok(result) => {
+                conn.execute_batch("RELEASE ratchet_op;").map_err(|e| e.to_string())?;
+                Ok(result)
+            }
+            Err(e) => {
+                let _ = conn.execute_batch("ROLLBACK TO ratchet_op; RELEASE ratchet_op;");
+                Err(e)
+            }
+        };
+    }
+    let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+    let result = f(&tx)?;
+    tx.commit().map_err(|e| e.to_string())?;
+    Ok(result)
+}
+
 pub fn ratchet_encrypt(
     conn: &Connection,
     remote_hash: &str,
     plaintext: &str
 ) -> Result<serde_json::Value, String> {
-    let mut state = SessionState::load_from_db(conn, remote_hash)?.ok_or("No session available")?;
+    ratchet_encrypt_cached(conn, &mut SessionCache::write_through(), remote_hash, plaintext)
+}
+
+pub fn ratchet_encrypt_cached(
+    conn: &Connection,
+    sessions: &mut SessionCache,
+    remote_hash: &str,
+    plaintext: &str
+) -> Result<serde_json::Value, String> {
+    in_transaction(conn, |tx| encrypt_in_session(tx, sessions, remote_hash, plaintext))
+}
+
+fn encrypt_in_session(
+    conn: &Connection,
+    sessions: &mut SessionCache,
+    remote_hash: &str,
+    plaintext: &str
+) -> Result<serde_json::Value, String> {
+    let mut state = sessions.get(conn, remote_hash)?.ok_or("No session available")?;
     
     // Capture the header key to use for THIS message's header encryption.
     // If we ratchet below, we update the state's header key for the NEXT chain/message,
     // but the receiver expects THIS header to be encrypted with the CURRENT (old) key.
     let header_key_for_encryption = state.send_header_key.clone().ok_or("No header key")?;
+    let awaiting_reply = state.is_awaiting_reply();
 
     if state.send_chain_key.is_none() {
         let root_key = decode_b64(state.root_key.as_ref().ok_or("No root key")?)?;
@@ -202,7 +267,6 @@ pub fn ratchet_encrypt(
     state.send_chain_key = Some(encode_b64(&new_ck));
     let n = state.sequence_number_send;
     state.sequence_number_send += 1;
-    state.save_to_db(conn, remote_hash)?;
 
     let ratchet_pub_bytes = decode_b64(&state.send_ratchet_key_public.clone().unwrap_or_default())?;
     let header_key_bytes = decode_b64(&header_key_for_encryption)?;
@@ -215,7 +279,7 @@ pub fn ratchet_encrypt(
     )?;
 
     let mut msg_payload = serde_json::json!({
-        "type": if n == 0 { 3 } else { 1 },
+        "type": if awaiting_reply { 3 } else { 1 },
         "body": encode_b64(&ciphertext),
         "nonce": encode_b64(&nonce_bytes), 
         "header_enc": header_enc,
@@ -223,118 +287,227 @@ pub fn ratchet_encrypt(
         "lh": lock_hash 
     });
 
-    if let Some(pq1) = state.pq_ct1.take() {
-        msg_payload["pq1"] = serde_json::Value::String(pq1);
-    }
-    if let Some(pq2) = state.pq_ct2.take() {
-        msg_payload["pq2"] = serde_json::Value::String(pq2);
-    }
-
-    if n == 0 {
-        if let Ok(Some(me)) = ProtocolIdentity::load_from_db(conn) {
+    // Until the peer answers, every message carries the PreKey header, so the peer can set
+    // up the session from whichever of them arrives first.
+    if awaiting_reply {
+        if let Some(pq1) = &state.pq_ct1 {
+            msg_payload["pq1"] = serde_json::Value::String(pq1.clone());
+        }
+        if let Some(pq2) = &state.pq_ct2 {
+            msg_payload["pq2"] = serde_json::Value::String(pq2.clone());
+        }
+        if let Ok(Some(me)) = sessions.identity(conn) {
             msg_payload["ik"] = serde_json::Value::String(me.identity_keys.public_key);
             msg_payload["pq_ik"] = serde_json::Value::String(me.identity_keys.pq_public_key);
         }
+    } else {
+        state.pq_ct1 = None;
+        state.pq_ct2 = None;
     }
     msg_payload["ek"] = serde_json::Value::String(state.send_ratchet_key_public.clone().unwrap_or_default());
 
-    state.save_to_db(conn, remote_hash)?;
+    sessions.put_durable(conn, remote_hash, state)?;
     Ok(msg_payload)
 }
 
+/// Nothing is written until the message has authenticated: a tampered or replayed
+/// message leaves every stored session, including a crossed one, untouched.
 pub fn ratchet_decrypt(
     conn: &Connection,
     remote_hash: &str,
     msg_obj: &serde_json::Value
 ) -> Result<String, String> {
-    let mut state_opt = SessionState::load_from_db(conn, remote_hash)?;
+    ratchet_decrypt_cached(conn, &mut SessionCache::write_through(), remote_hash, msg_obj)
+}
+
+pub fn ratchet_decrypt_cached(
+    conn: &Connection,
+    sessions: &mut SessionCache,
+    remote_hash: &str,
+    msg_obj: &serde_json::Value
+) -> Result<String, String> {
+    in_transaction(conn, |tx| decrypt_in_session(tx, sessions, remote_hash, msg_obj))
+}
 
-    if state_opt.is_none() {
-        let alice_ik_b64 = msg_obj.get("ik").and_then(|v| v.as_str()).ok_or("Missing IK in PreKey")?;
-        let alice_ek_b64 = msg_obj.get("ek").and_then(|v| v.as_str()).ok_or("Missing EK in PreKey")?;
+// The cache is updated last in every path, so an error never leaves it ahead of the
+// rolled-back transaction.
+fn decrypt_in_session(
+    conn: &Connection,
+    sessions: &mut SessionCache,
+    remote_hash: &str,
+    msg_obj: &serde_json::Value
+) -> Result<String, String> {
+    let Some(mut state) = sessions.get(conn, remote_hash)? else {
+        let mut state = build_responder_session(conn, sessions, msg_obj)?;
+        let plaintext = decrypt_with_state(&mut state, msg_obj)?;
+        sessions.put(conn, remote_hash, state)?;
+        return Ok(plaintext);
+    };
 
-        let alice_ik_bytes = decode_b64(alice_ik_b64)?;
-        let alice_ek_bytes = decode_b64(alice_ek_b64)?;
-        
-        let alice_ik = X25519PublicKey::from(ed25519_pub_to_x25519(&alice_ik_bytes)?);
-        let alice_ek = X25519PublicKey::from(<[u8; 32]>::try_from(alice_ek_bytes).map_err(|_| "Invalid EK size")?);
-
-        let identity = ProtocolIdentity::load_from_db(conn)?.ok_or("No identity")?;
-        let bob_ik_priv = decode_b64(&identity.identity_keys.private_key)?;
-        let bob_ik = ed25519_priv_to_x25519(&bob_ik_priv)?;
-        let bob_spk_priv = decode_b64(&identity.signed_pre_key.private_key)?;
-        let bob_spk = StaticSecret::from(<[u8; 32]>::try_from(bob_spk_priv).map_err(|_| "Invalid SPK size")?);
-
-        let dh1 = bob_spk.diffie_hellman(&alice_ik);
-        let dh2 = bob_ik.diffie_hellman(&alice_ek);
-        let dh3 = bob_spk.diffie_hellman(&alice_ek);
-        
-        let mut km = Vec::new();
-        km.extend_from_slice(dh1.as_bytes());
-        km.extend_from_slice(dh2.as_bytes());
-        km.extend_from_slice(dh3.as_bytes());
+    let awaiting_reply = state.is_awaiting_reply();
+    let sent_pre_key = state.sequence_number_send > 0;
+    let my_base_key = state.send_ratchet_key_public.clone().unwrap_or_default();
 
-        let pq_ct1_b64 = msg_obj.get("pq1").and_then(|v| v.as_str()).ok_or("Missing PQ CT1")?;
-        let pq_ct2_b64 = msg_obj.get("pq2").and_then(|v| v.as_str()).ok_or("Missing PQ CT2")?;
-        
-        let pq_ct1 = kyber1024::Ciphertext::from_bytes(&decode_b64(pq_ct1_b64)?).map_err(|_| "Invalid PQ CT1")?;
-        let pq_ct2 = kyber1024::Ciphertext::from_bytes(&decode_b64(pq_ct2_b64)?).map_err(|_| "Invalid PQ CT2")?;
-        
-        let pq_id_sk = kyber1024::SecretKey::from_bytes(&decode_b64(&identity.identity_keys.pq_private_key)?).map_err(|_| "Invalid PQ ID SK")?;
-        let pq_spk_sk = kyber1024::SecretKey::from_bytes(&decode_b64(&identity.signed_pre_key.pq_private_key)?).map_err(|_| "Invalid PQ SPK SK")?;
-        
-        let ss1 = kyber1024::decapsulate(&pq_ct1, &pq_id_sk);
-        let ss2 = kyber1024::decapsulate(&pq_ct2, &pq_spk_sk);
-        
-        km.extend_from_slice(ss1.as_bytes());
-        km.extend_from_slice(ss2.as_bytes());
-        
-        let hk = Hkdf::<Sha256>::new(None, &km);
-        let mut root_key_bytes = [0u8; 32];
-        hk.expand(b"EntropyV1 X3DH+PQ", &mut root_key_bytes).map_err(|e| e.to_string())?;
+    match decrypt_with_state(&mut state, msg_obj) {
+        Ok(plaintext) => {
+            if state.crossed_until.is_some_and(|until| messages::now_millis() >= until) {
+                SessionState::delete_crossed_from_db(conn, remote_hash)?;
+                state.crossed_until = None;
+            }
+            sessions.put(conn, remote_hash, state)?;
+            Ok(plaintext)
+        }
+        Err(e) => {
+            // Messages from the losing handshake keep arriving, possibly out of order, for
+            // a while after the winner has been picked.
+            if state.crossed_until.is_some() {
+                if let Some(mut crossed) = SessionState::load_crossed_from_db(conn, remote_hash)? {
+                    if let Ok(plaintext) = decrypt_with_state(&mut crossed, msg_obj) {
+                        crossed.save_crossed_to_db(conn, remote_hash)?;
+                        return Ok(plaintext);
+                    }
+                }
+            }
+            if awaiting_reply && state.crossed_until.is_none() && is_pre_key_message(msg_obj) {
+                return resolve_simultaneous_initiation(conn, sessions, remote_hash, state, &my_base_key, sent_pre_key, msg_obj);
+            }
+            Err(e)
+        }
+    }
+}
 
-        let hk_gen = Hkdf::<Sha256>::new(None, &root_key_bytes);
-        let mut hk_send = [0u8; 32];
-        let mut hk_recv = [0u8; 32];
-        
-        hk_gen.expand(b"EntropyV1 HeaderSend", &mut hk_recv).map_err(|e| e.to_string())?;
-        hk_gen.expand(b"EntropyV1 HeaderRecv", &mut hk_send).map_err(|e| e.to_string())?;
-
-        let (rk_1, ck_1, _hk_ignored) = kdf_rk(&root_key_bytes, dh3.as_bytes())?;
-        let new_state = SessionState {
-            remote_identity_key: Some(alice_ik_b64.to_string()),
-            root_key: Some(encode_b64(&rk_1)),
-            send_chain_key: None, 
-            recv_chain_key: Some(encode_b64(&ck_1)), 
-            send_ratchet_key_private: Some(encode_b64(bob_spk.to_bytes().as_slice())),
-            send_ratchet_key_public: Some(identity.signed_pre_key.public_key.clone()),
-            recv_ratchet_key: Some(alice_ek_b64.to_string()), 
-            sequence_number_send: 0,
-            sequence_number_recv: 0,
-            prev_sequence_number_send: 0,
-            send_header_key: Some(encode_b64(&hk_send)), 
-            recv_header_key: Some(encode_b64(&hk_recv)),
-            next_send_header_key: None,
-            next_recv_header_key: None,
-            skipped_message_keys: HashMap::new(),
-            is_verified: false,
-            verified_identity_key: Some(alice_ik_b64.to_string()),
-            verification_timestamp: None,
-            last_sent_hash: None,
-            last_recv_hash: None,
-            pq_ct1: msg_obj.get("pq1").and_then(|v| v.as_str()).map(|s| s.to_string()),
-            pq_ct2: msg_obj.get("pq2").and_then(|v| v.as_str()).map(|s| s.to_string()),
-            pq_shared_secret: {
-                let mut combined = ss1.as_bytes().to_vec();
-                combined.extend_from_slice(ss2.as_bytes());
-                Some(encode_b64(&combined))
-            },
-        };
-        new_state.save_to_db(conn, remote_hash)?;
-        state_opt = Some(new_state);
+fn is_pre_key_message(msg_obj: &serde_json::Value) -> bool {
+    msg_obj.get("ik").is_some() && msg_obj.get("pq1").is_some()
+}
+
+/// Both peers initiated before either PreKey message arrived. Each side runs the same
+/// comparison, so exactly one handshake survives; the losing one is kept for
+/// [`CROSSED_SESSION_TTL_MS`] to read what the peer sent before it switched over.
+fn resolve_simultaneous_initiation(
+    conn: &Connection,
+    sessions: &mut SessionCache,
+    remote_hash: &str,
+    mut mine: SessionState,
+    my_base_key: &str,
+    sent_pre_key: bool,
+    msg_obj: &serde_json::Value
+) -> Result<String, String> {
+    let identity = sessions.identity(conn)?.ok_or("No identity")?;
+    let their_ik = msg_obj["ik"].as_str().ok_or("Missing IK in PreKey")?;
+    let their_ek = msg_obj["ek"].as_str().ok_or("Missing EK in PreKey")?;
+
+    let mut theirs = build_responder_session(conn, sessions, msg_obj)?;
+    let plaintext = decrypt_with_state(&mut theirs, msg_obj)?;
+
+    // Identity keys decide; base keys only break the tie between two devices sharing one identity.
+    let we_win = sent_pre_key && (identity.identity_keys.public_key.as_str(), my_base_key) < (their_ik, their_ek);
+    if we_win {
+        theirs.save_crossed_to_db(conn, remote_hash)?;
+        mine.crossed_until = Some(messages::now_millis() + CROSSED_SESSION_TTL_MS);
+        sessions.put(conn, remote_hash, mine)?;
+    } else {
+        if mine.crossed_until.is_some() {
+            SessionState::delete_crossed_from_db(conn, remote_hash)?;
+        }
+        sessions.put(conn, remote_hash, theirs)?;
     }
+    Ok(plaintext)
+}
 
-    let mut state = state_opt.unwrap();
+fn build_responder_session(
+    conn: &Connection,
+    sessions: &mut SessionCache,
+    msg_obj: &serde_json::Value
+) -> Result<SessionState, String> {
+    let alice_ik_b64 = msg_obj.get("ik").and_then(|v| v.as_str()).ok_or("Missing IK in PreKey")?;
+    let alice_ek_b64 = msg_obj.get("ek").and_then(|v| v.as_str()).ok_or("Missing EK in PreKey")?;
+
+    let alice_ik_bytes = decode_b64(alice_ik_b64)?;
+    let alice_ek_bytes = decode_b64(alice_ek_b64)?;
+    
+    let alice_ik = X25519PublicKey::from(ed25519_pub_to_x25519(&alice_ik_bytes)?);
+    let alice_ek = X25519PublicKey::from(<[u8; 32]>::try_from(alice_ek_bytes).map_err(|_| "Invalid EK size")?);
+
+    let identity = sessions.identity(conn)?.ok_or("No identity")?;
+    let bob_ik_priv = decode_b64(&identity.identity_keys.private_key)?;
+    let bob_ik = ed25519_priv_to_x25519(&bob_ik_priv)?;
+    let bob_spk_priv = decode_b64(&identity.signed_pre_key.private_key)?;
+    let bob_spk = StaticSecret::from(<[u8; 32]>::try_from(bob_spk_priv).map_err(|_| "Invalid SPK size")?);
+
+    let dh1 = bob_spk.diffie_hellman(&alice_ik);
+    let dh2 = bob_ik.diffie_hellman(&alice_ek);
+    let dh3 = bob_spk.diffie_hellman(&alice_ek);
+    
+    let mut km = Vec::new();
+    km.extend_from_slice(dh1.as_bytes());
+    km.extend_from_slice(dh2.as_bytes());
+    km.extend_from_slice(dh3.as_bytes());
+
+    let pq_ct1_b64 = msg_obj.get("pq1").and_then(|v| v.as_str()).ok_or("Missing PQ CT1")?;
+    let pq_ct2_b64 = msg_obj.get("pq2").and_then(|v| v.as_str()).ok_or("Missing PQ CT2")?;
+    
+    let pq_ct1 = kyber1024::Ciphertext::from_bytes(&decode_b64(pq_ct1_b64)?).map_err(|_| "Invalid PQ CT1")?;
+    let pq_ct2 = kyber1024::Ciphertext::from_bytes(&decode_b64(pq_ct2_b64)?).map_err(|_| "Invalid PQ CT2")?;
+    
+    let pq_id_sk = kyber1024::SecretKey::from_bytes(&decode_b64(&identity.identity_keys.pq_private_key)?).map_err(|_| "Invalid PQ ID SK")?;
+    let pq_spk_sk = kyber1024::SecretKey::from_bytes(&decode_b64(&identity.signed_pre_key.pq_private_key)?).map_err(|_| "Invalid PQ SPK SK")?;
+    
+    let ss1 = kyber1024::decapsulate(&pq_ct1, &pq_id_sk);
+    let ss2 = kyber1024::decapsulate(&pq_ct2, &pq_spk_sk);
+    
+    km.extend_from_slice(ss1.as_bytes());
+    km.extend_from_slice(ss2.as_bytes());
+    
+    let hk = Hkdf::<Sha256>::new(None, &km);
+    let mut root_key_bytes = [0u8; 32];
+    hk.expand(b"EntropyV1 X3DH+PQ", &mut root_key_bytes).map_err(|e| e.to_string())?;
+
+    let hk_gen = Hkdf::<Sha256>::new(None, &root_key_bytes);
+    let mut hk_send = [0u8; 32];
+    let mut hk_recv = [0u8; 32];
+    
+    hk_gen.expand(b"EntropyV1 HeaderSend", &mut hk_recv).map_err(|e| e.to_string())?;
+    hk_gen.expand(b"EntropyV1 HeaderRecv", &mut hk_send).map_err(|e| e.to_string())?;
+
+    let (rk_1, ck_1, _hk_ignored) = kdf_rk(&root_key_bytes, dh3.as_bytes())?;
+    Ok(SessionState {
+        remote_identity_key: Some(alice_ik_b64.to_string()),
+        root_key: Some(encode_b64(&rk_1)),
+        send_chain_key: None, 
+        recv_chain_key: Some(encode_b64(&ck_1)), 
+        send_ratchet_key_private: Some(encode_b64(bob_spk.to_bytes().as_slice())),
+        send_ratchet_key_public: Some(identity.signed_pre_key.public_key.clone()),
+        recv_ratchet_key: Some(alice_ek_b64.to_string()), 
+        sequence_number_send: 0,
+        sequence_number_recv: 0,
+        prev_sequence_number_send: 0,
+        send_header_key: Some(encode_b64(&hk_send)), 
+        recv_header_key: Some(encode_b64(&hk_recv)),
+        next_send_header_key: None,
+        next_recv_header_key: None,
+        skipped_message_keys: HashMap::new(),
+        is_verified: false,
+        verified_identity_key: Some(alice_ik_b64.to_string()),
+        verification_timestamp: None,
+        last_sent_hash: None,
+        last_recv_hash: None,
+        pq_ct1: msg_obj.get("pq1").and_then(|v| v.as_str()).map(|s| s.to_string()),
+        pq_ct2: msg_obj.get("pq2").and_then(|v| v.as_str()).map(|s| s.to_string()),
+        pq_shared_secret: {
+            let mut combined = ss1.as_bytes().to_vec();
+            combined.extend_from_slice(ss2.as_bytes());
+            Some(encode_b64(&combined))
+        },
+        crossed_until: None,
+    })
+}
+
+/// Advances `state` to decrypt `msg_obj`. Nothing is persisted; the caller saves the
+/// state only once the message has been authenticated.
+fn decrypt_with_state(
+    state: &mut SessionState,
+    msg_obj: &serde_json::Value
+) -> Result<String, String> {
     let header_enc = msg_obj["header_enc"].as_str().ok_or("Missing header_enc")?;
     let header_nonce = msg_obj["header_nonce"].as_str().ok_or("Missing header_nonce")?;
 
@@ -347,8 +520,6 @@ pub fn ratchet_decrypt(
 
     let lookup_key = format!("{}_{}", ratchet_pub_b64, n);
     if let Some(mk_b64) = state.skipped_message_keys.remove(&lookup_key) {
-        state.save_to_db(conn, remote_hash)?; 
-        
         let mk = decode_b64(&mk_b64)?;
         let cipher = Aes256Gcm::new_from_slice(&mk).map_err(|e| e.to_string())?;
     
@@ -370,7 +541,7 @@ pub fn ratchet_decrypt(
     };
 
     if is_new_ratchet {
-        skip_message_keys(&mut state, pn)?;
+        skip_message_keys(state, pn)?;
         
         let root_key = decode_b64(state.root_key.as_ref().ok_or("No root key")?)?;
         let remote_ratchet_bytes = decode_b64(ratchet_pub_b64)?;
@@ -394,7 +565,7 @@ pub fn ratchet_decrypt(
         state.recv_header_key = state.next_recv_header_key.take(); 
     }
 
-    skip_message_keys(&mut state, n)?;
+    skip_message_keys(state, n)?;
     
     let current_ck = decode_b64(state.recv_chain_key.as_ref().ok_or("No recv chain")?)?;
     let (next_ck, mk) = kdf_ck(&current_ck)?;
@@ -424,8 +595,6 @@ pub fn ratchet_decrypt(
     hasher.update(&ct);
     state.last_recv_hash = Some(hex::encode(hasher.finalize()));
 
-    state.save_to_db(conn, remote_hash)?;
-    
     String::from_utf8(plaintext).map_err(|e| e.to_string())
 }
 
@@ -514,6 +683,19 @@ pub fn unseal_sender(
     Ok((envelope.sender, envelope.message))
 }
 
+pub fn unseal_with_identity(
+    identity: &ProtocolIdentity,
+    sealed_obj: &serde_json::Value
+) -> Result<(String, serde_json::Value), String> {
+    let mut sk_bytes = [0u8; 32];
+    sk_bytes.copy_from_slice(&decode_b64(&identity.identity_keys.private_key)?);
+    let my_sk = StaticSecret::from(sk_bytes);
+
+    let my_pq_sk = kyber1024::SecretKey::from_bytes(&decode_b64(&identity.identity_keys.pq_private_key)?).map_err(|_| "Invalid PQ SK")?;
+
+    unseal_sender(sealed_obj, &my_sk, &my_pq_sk)
+}
+
 pub fn save_pending_message(conn: &Connection, msg: &PendingMessage) -> Result<(), String> {
     conn.execute(
         "INSERT OR REPLACE INTO pending_messages (id, recipient_hash, body, timestamp, retries) VALUES (?1, ?2, ?3, ?4, ?5);",
@@ -600,70 +782,3 @@ pub fn secure_nuke_database(db_path: &std::path::Path) -> Result<(), String> {
     }
     Ok(())
 }
-pub fn save_decrypted_message(
-    conn: &rusqlite::Connection,
-    peer_hash: &str,
-    msg: &serde_json::Value
-) -> Result<(), String> {
-    conn.execute(
-        "INSERT OR REPLACE INTO messages (id, peer_hash, timestamp, content, sender_hash, type, is_mine, status, reply_to_id, attachment_json)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
-        params![
-            msg["id"].as_str().ok_or("Missing id")?,
-            peer_hash,
-            msg["timestamp"].as_u64().unwrap_or(0),
-            msg["content"].as_str().unwrap_or(""),
-            msg["senderHash"].as_str().unwrap_or(""),
-            msg["type"].as_str().unwrap_or("text"),
-            if msg["isMine"].as_bool().unwrap_or(false) { 1 } else { 0 },
-            msg["status"].as_str().unwrap_or("sent"),
-            msg["replyTo"]["id"].as_str(),
-            msg["attachment"].as_object().map(|_| serde_json::to_string(&msg["attachment"]).unwrap())
-        ]
-    ).map_err(|e| e.to_string())?;
-    Ok(())
-}
-
-pub fn search_messages(
-    conn: &rusqlite::Connection,
-    query: &str
-) -> Result<Vec<serde_json::Value>, String> {
-    let mut stmt = conn.prepare(
-        "SELECT id, peer_hash, timestamp, content, sender_hash, type, is_mine, status, reply_to_id, attachment_json
-         FROM messages WHERE content LIKE ?1 ORDER BY timestamp DESC LIMIT 100"
-    ).map_err(|e| e.to_string())?;
-
-    let rows = stmt.query_map([format!("%{}%", query)], |row| {
-        let id: String = row.get(0)?;
-        let peer_hash: String = row.get(1)?;
-        let timestamp: u64 = row.get(2)?;
-        let content: String = row.get(3)?;
-        let sender_hash: String = row.get(4)?;
-        let msg_type: String = row.get(5)?;
-        let is_mine: bool = row.get::<_, i32>(6)? == 1;
-        let status: String = row.get(7)?;
-        let reply_to_id: Option<String> = row.get(8)?;
-        let attachment_json: Option<String> = row.get(9)?;
-        
-        let attachment: Option<serde_json::Value> = attachment_json.and_then(|s| serde_json::from_str(&s).ok());
-
-        Ok(serde_json::json!({
-            "id": id,
-            "peerHash": peer_hash,
-            "timestamp": timestamp,
-            "content": content,
-            "senderHash": sender_hash,
-            "type": msg_type,
-            "isMine": is_mine,
-            "status": status,
-            "replyTo": reply_to_id.map(|id| serde_json::json!({ "id": id })),
-            "attachment": attachment
-        }))
-    }).map_err(|e| e.to_string())?;
-
-    let mut results = Vec::new();
-    for row in rows {
-        results.push(row.map_err(|e| e.to_string())?);
-    }
-    Ok(results)
-}
@@ -0,0 +1,157 @@
+use aes_gcm::{Aes256Gcm, Nonce, aead::{Aead, KeyInit, Payload}};
+use hkdf::Hkdf;
+use rand::{RngCore, thread_rng};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::secure_nuke_database;
+
+/// Fallback for secrets the OS keyring could not hold. It is encrypted under a key derived
+/// from the machine ID and the salt stored in the file, which keeps the secrets out of
+/// plaintext backups and casual copies. It is obfuscation, not protection: the machine ID
+/// is readable by every local user (e.g. the world-readable `/etc/machine-id`), so anyone
+/// who has the file and can read or guess that ID can decrypt it.
+#[derive(Serialize, Deserialize)]
+struct SecretStoreFile {
+    version: u32,
+    salt: String,
+    entries: HashMap<String, String>,
+}
+
+pub fn secret_store_path(app_data_dir: &Path) -> PathBuf {
+    app_data_dir.join("secrets.store")
+}
+
+#[cfg(target_os = "linux")]
+fn machine_id() -> Option<String> {
+    ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+        .iter()
+        .find_map(|p| std::fs::read_to_string(p).ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+#[cfg(target_os = "macos")]
+fn machine_id() -> Option<String> {
+    let out = std::process::Command::new("ioreg").args(["-rd1", "-c", "IOPlatformExpertDevice"]).output().ok()?;
+    String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .find(|l| l.contains("IOPlatformUUID"))
+        .and_then(|l| l.split('"').nth(3))
+        .map(|s| s.to_string())
+}
+
+#[cfg(target_os = "windows")]
+fn machine_id() -> Option<String> {
+    use std::os::windows::process::CommandExt;
+    // Keep `reg` from flashing a console window over the GUI.
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+    let out = std::process::Command::new("reg")
+        .args(["query", r"HKLM\SOFTWARE\Microsoft\Cryptography", "/v", "MachineGuid"])
+        .creation_flags(CREATE_NO_WINDOW)
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .find(|l| l.contains("MachineGuid"))
+        .and_then(|l| l.split_whitespace().last())
+        .map(|s| s.to_string())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+fn machine_id() -> Option<String> {
+    None
+}
+
+fn store_cipher(salt_hex: &str) -> Result<Aes256Gcm, String> {
+    let machine = machine_id().ok_or("No machine-bound key is available on this system")?;
+    let salt = hex::decode(salt_hex).map_err(|e| e.to_string())?;
+    let hk = Hkdf::<Sha256>::new(Some(&salt), machine.as_bytes());
+    let mut key = [0u8; 32];
+    hk.expand(b"Entropy secret store v1", &mut key).map_err(|_| "HKDF expand failed".to_string())?;
+    Aes256Gcm::new_from_slice(&key).map_err(|e| format!("{:?}", e))
+}
+
+fn load_store(path: &Path) -> Result<SecretStoreFile, String> {
+    if !path.exists() {
+        let mut salt = [0u8; 16];
+        thread_rng().fill_bytes(&mut salt);
+        return Ok(SecretStoreFile { version: 1, salt: hex::encode(salt), entries: HashMap::new() });
+    }
+    let data = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    serde_json::from_str(&data).map_err(|e| format!("Corrupt secret store: {}", e))
+}
+
+fn save_store(path: &Path, store: &SecretStoreFile) -> Result<(), String> {
+    let tmp = path.with_extension("store.tmp");
+    std::fs::write(&tmp, serde_json::to_vec(store).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+    std::fs::rename(&tmp, path).map_err(|e| e.to_string())
+}
+
+pub fn store_fallback_secret(app_data_dir: &Path, key: &str, value: &str) -> Result<(), String> {
+    let path = secret_store_path(app_data_dir);
+    let mut store = load_store(&path)?;
+    let cipher = store_cipher(&store.salt)?;
+
+    let mut nonce = [0u8; 12];
+    thread_rng().fill_bytes(&mut nonce);
+    // The entry name is bound in as AAD so ciphertexts cannot be swapped between keys.
+    let ct = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: value.as_bytes(), aad: key.as_bytes() })
+        .map_err(|_| "Secret encryption failed".to_string())?;
+
+    let mut combined = nonce.to_vec();
+    combined.extend_from_slice(&ct);
+    store.entries.insert(key.to_string(), hex::encode(combined));
+    save_store(&path, &store)
+}
+
+pub fn load_fallback_secret(app_data_dir: &Path, key: &str) -> Result<Option<String>, String> {
+    let path = secret_store_path(app_data_dir);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let store = load_store(&path)?;
+    let Some(entry) = store.entries.get(key) else { return Ok(None) };
+
+    let combined = hex::decode(entry).map_err(|e| e.to_string())?;
+    if combined.len() < 12 {
+        return Err("Corrupt secret store entry".to_string());
+    }
+    let cipher = store_cipher(&store.salt)?;
+    let plain = cipher.decrypt(Nonce::from_slice(&combined[..12]), Payload { msg: &combined[12..], aad: key.as_bytes() })
+        .map_err(|_| "Secret store was created on another machine or is corrupt".to_string())?;
+    String::from_utf8(plain).map(Some).map_err(|e| e.to_string())
+}
+
+pub fn remove_fallback_secret(app_data_dir: &Path, key: &str) -> Result<(), String> {
+    let path = secret_store_path(app_data_dir);
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut store = load_store(&path)?;
+    if store.entries.remove(key).is_some() {
+        save_store(&path, &store)?;
+    }
+    Ok(())
+}
+
+/// Moves secrets left in plaintext `<key>.secret` files by older versions into the keyring,
+/// or the encrypted store if `keyring_store` refuses them, then shreds the files.
+pub fn migrate_plaintext_secrets(app_data_dir: &Path, keyring_store: impl Fn(&str, &str) -> bool) -> Result<(), String> {
+    let Ok(entries) = std::fs::read_dir(app_data_dir) else { return Ok(()) };
+    for path in entries.filter_map(|e| e.ok().map(|e| e.path())) {
+        if path.extension().and_then(|e| e.to_str()) != Some("secret") {
+            continue;
+        }
+        let Some(key) = path.file_stem().and_then(|s| s.to_str()).map(|s| s.to_string()) else { continue };
+        let value = std::fs::read_to_string(&path).map_err(|e| e.to_string())?;
+
+        if !keyring_store(&key, &value) {
+            store_fallback_secret(app_data_dir, &key, &value)?;
+        }
+        secure_nuke_database(&path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use rusqlite::Connection;
+use std::collections::HashMap;
+
+use super::types::{ProtocolIdentity, SessionState};
+
+/// Sessions kept decoded on the database thread. Enough for every active conversation.
+pub const SESSION_CACHE_CAPACITY: usize = 256;
+
+/// Dirty sessions are written out once this many have piled up, even mid-burst.
+pub const SESSION_FLUSH_BATCH: usize = 64;
+
+struct CachedSession {
+    state: SessionState,
+    last_used: u64,
+    dirty: bool,
+}
+
+/// Decoded sessions and the local identity, so draining a backlog does not re-parse and
+/// rewrite the session JSON for every message.
+///
+/// Received messages only update the cache and are persisted in batches by [`Self::flush`].
+/// Sent messages are always written before the ciphertext is returned, since losing a
+/// send-chain step would reuse message keys. Anything that writes sessions or the identity
+/// behind the cache's back must [`Self::clear`] it afterwards.
+pub struct SessionCache {
+    capacity: usize,
+    write_through: bool,
+    sessions: HashMap<String, CachedSession>,
+    identity: Option<ProtocolIdentity>,
+    clock: u64,
+}
+
+impl SessionCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            write_through: false,
+            sessions: HashMap::new(),
+            identity: None,
+            clock: 0,
+        }
+    }
+
+    /// A single-use cache that persists every update immediately, for callers that do
+    /// not keep one around.
+    pub fn write_through() -> Self {
+        Self { write_through: true, ..Self::new(1) }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Returns a copy of the session with `peer_hash`, loading it on a miss. Callers
+    /// work on the copy and [`Self::put`] it back only once the operation has succeeded.
+    pub fn get(&mut self, conn: &Connection, peer_hash: &str) -> Result<Option<SessionState>, String> {
+        let now = self.tick();
+        if let Some(cached) = self.sessions.get_mut(peer_hash) {
+            cached.last_used = now;
+            return Ok(Some(cached.state.clone()));
+        }
+        let Some(state) = SessionState::load_from_db(conn, peer_hash)? else {
+            return Ok(None);
+        };
+        self.insert(conn, peer_hash, state.clone(), false)?;
+        Ok(Some(state))
+    }
+
+    /// Stores an updated session, to be persisted by the next flush.
+    pub fn put(&mut self, conn: &Connection, peer_hash: &str, state: SessionState) -> Result<(), String> {
+        if self.write_through {
+            return self.put_durable(conn, peer_hash, state);
+        }
+        self.insert(conn, peer_hash, state, true)
+    }
+
+    /// Stores an updated session and writes it immediately.
+    pub fn put_durable(&mut self, conn: &Connection, peer_hash: &str, state: SessionState) -> Result<(), String> {
+        state.save_to_db(conn, peer_hash)?;
+        self.insert(conn, peer_hash, state, false)
+    }
+
+    fn insert(&mut self, conn: &Connection, peer_hash: &str, state: SessionState, dirty: bool) -> Result<(), String> {
+        if !self.sessions.contains_key(peer_hash) && self.sessions.len() >= self.capacity {
+            self.evict_oldest(conn)?;
+        }
+        let last_used = self.tick();
+        self.sessions.insert(peer_hash.to_string(), CachedSession { state, last_used, dirty });
+        Ok(())
+    }
+
+    fn evict_oldest(&mut self, conn: &Connection) -> Result<(), String> {
+        let Some(oldest) = self.sessions.iter().min_by_key(|(_, c)| c.last_used).map(|(k, _)| k.clone()) else {
+            return Ok(());
+        };
+        if let Some(evicted) = self.sessions.get(&oldest) {
+            if evicted.dirty {
+                evicted.state.save_to_db(conn, &oldest)?;
+            }
+        }
+        self.sessions.remove(&oldest);
+        Ok(())
+    }
+
+    pub fn identity(&mut self, conn: &Connection) -> Result<Option<ProtocolIdentity>, String> {
+        if self.identity.is_none() {
+            self.identity = ProtocolIdentity::load_from_db(conn)?;
+        }
+        Ok(self.identity.clone())
+    }
+
+    pub fn dirty_len(&self) -> usize {
+        self.sessions.values().filter(|c| c.dirty).count()
+    }
+
+    /// Writes every dirty session in one transaction.
+    pub fn flush(&mut self, conn: &Connection) -> Result<(), String> {
+        if self.dirty_len() == 0 {
+            return Ok(());
+        }
+        super::in_transaction(conn, |tx| {
+            for (peer_hash, cached) in self.sessions.iter().filter(|(_, c)| c.dirty) {
+                cached.state.save_to_db(tx, peer_hash)?;
+            }
+            Ok(())
+        })?;
+        for cached in self.sessions.values_mut() {
+            cached.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Drops everything cached. Flush first unless the vault it came from is gone.
+    pub fn clear(&mut self) {
+        self.sessions.clear();
+        self.identity = None;
+    }
+}
@@ -6,7 +6,8 @@ use x25519_dalek::{StaticSecret, PublicKey as X25519PublicKey};
 use rand::{RngCore, thread_rng};
 use pqcrypto_kyber::kyber1024;
 use pqcrypto_traits::kem::{PublicKey as PQPubKey, SecretKey as PQSecretKey};
-use crate::protocol::utils::encode_b64;
+use sha2::{Digest, Sha256};
+use crate::protocol::utils::{decode_b64, encode_b64};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct IdentityKeys {
@@ -73,6 +74,10 @@ pub struct SessionState {
     pub pq_ct1: Option<String>,
     pub pq_ct2: Option<String>,
     pub pq_shared_secret: Option<String>, 
+
+    /// While a crossed session from a simultaneous initiation is kept, when it may go (ms).
+    #[serde(default)]
+    pub crossed_until: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -111,53 +116,54 @@ pub struct PendingMessage {
     pub retries: u32,
 }
 
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageSearchFilters {
+    pub peer_hash: Option<String>,
+    pub sender_hash: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub msg_type: Option<String>,
+    pub has_attachment: Option<bool>,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct SealedEnvelope {
     pub sender: String, 
     pub message: serde_json::Value,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BatchItem {
+    pub sender: Option<String>,
+    pub envelope: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct BatchResult {
+    pub sender: Option<String>,
+    pub plaintext: Option<String>,
+    /// Unsealed group messages, returned for the caller to decrypt with the group state.
+    pub message: Option<serde_json::Value>,
+    pub error: Option<String>,
+}
+
 pub fn init_database(conn: &Connection) -> Result<(), String> {
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS vault (key TEXT PRIMARY KEY, value TEXT);",
-        [],
-    ).map_err(|e| e.to_string())?;
-
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS pending_messages (id TEXT PRIMARY KEY, recipient_hash TEXT, body TEXT, timestamp INTEGER, retries INTEGER);",
-        [],
-    ).map_err(|e| e.to_string())?;
-    
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS groups (group_id TEXT PRIMARY KEY, state TEXT);",
-        [],
-    ).map_err(|e| e.to_string())?;
-
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS messages (
-            id TEXT PRIMARY KEY,
-            peer_hash TEXT,
-            timestamp INTEGER,
-            content TEXT,
-            sender_hash TEXT,
-            type TEXT,
-            is_mine INTEGER,
-            status TEXT,
-            reply_to_id TEXT,
-            attachment_json TEXT
-        );",
-        [],
-    ).map_err(|e| e.to_string())?;
-
-    conn.execute(
-        "CREATE INDEX IF NOT EXISTS idx_messages_peer ON messages(peer_hash);",
-        [],
-    ).map_err(|e| e.to_string())?;
+    crate::protocol::migrations::run_migrations(conn)?;
+
+    // Overwrite deleted content so expired messages cannot be recovered from free pages.
+    conn.pragma_update(None, "secure_delete", true).map_err(|e| e.to_string())?;
 
     Ok(())
 }
 
 impl ProtocolIdentity {
+    /// The hash peers address us by: SHA-256 of the identity public key.
+    pub fn identity_hash(&self) -> Result<String, String> {
+        let key = decode_b64(&self.identity_keys.public_key)?;
+        Ok(hex::encode(Sha256::digest(&key)))
+    }
+
     pub fn save_to_db(&self, conn: &Connection) -> Result<(), String> {
         let json = serde_json::to_string(self).map_err(|e| e.to_string())?;
         conn.execute(
@@ -204,17 +210,48 @@ impl ProtocolIdentity {
 
 impl SessionState {
     pub fn save_to_db(&self, conn: &Connection, peer_hash: &str) -> Result<(), String> {
+        self.save_under_key(conn, &format!("session_{}", peer_hash))
+    }
+
+    pub fn load_from_db(conn: &Connection, peer_hash: &str) -> Result<Option<Self>, String> {
+        Self::load_under_key(conn, &format!("session_{}", peer_hash))
+    }
+
+    /// The losing side of a simultaneous initiation, kept only to decrypt messages the
+    /// peer sent before it switched to our session.
+    pub fn save_crossed_to_db(&self, conn: &Connection, peer_hash: &str) -> Result<(), String> {
+        self.save_under_key(conn, &format!("crossed_session_{}", peer_hash))
+    }
+
+    pub fn load_crossed_from_db(conn: &Connection, peer_hash: &str) -> Result<Option<Self>, String> {
+        Self::load_under_key(conn, &format!("crossed_session_{}", peer_hash))
+    }
+
+    pub fn delete_crossed_from_db(conn: &Connection, peer_hash: &str) -> Result<(), String> {
+        conn.execute(
+            "DELETE FROM vault WHERE key = ?1;",
+            params![format!("crossed_session_{}", peer_hash)],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// True for an outbound session whose PreKey message has not been answered yet.
+    pub fn is_awaiting_reply(&self) -> bool {
+        self.send_chain_key.is_some() && self.recv_chain_key.is_none()
+    }
+
+    fn save_under_key(&self, conn: &Connection, key: &str) -> Result<(), String> {
         let json = serde_json::to_string(self).map_err(|e| e.to_string())?;
         conn.execute(
             "INSERT OR REPLACE INTO vault (key, value) VALUES (?1, ?2);",
-            params![format!("session_{}", peer_hash), json],
+            params![key, json],
         ).map_err(|e| e.to_string())?;
         Ok(())
     }
 
-    pub fn load_from_db(conn: &Connection, peer_hash: &str) -> Result<Option<Self>, String> {
+    fn load_under_key(conn: &Connection, key: &str) -> Result<Option<Self>, String> {
         let mut stmt = conn.prepare("SELECT value FROM vault WHERE key = ?1;").map_err(|e| e.to_string())?;
-        let mut rows = stmt.query([format!("session_{}", peer_hash)]).map_err(|e| e.to_string())?;
+        let mut rows = stmt.query([key]).map_err(|e| e.to_string())?;
         if let Some(row) = rows.next().map_err(|e| e.to_string())? {
             let json: String = row.get(0).map_err(|e| e.to_string())?;
             let state: SessionState = serde_json::from_str(&json).map_err(|e| e.to_string())?;
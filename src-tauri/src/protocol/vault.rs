@@ -0,0 +1,423 @@
+use hkdf::Hkdf;
+use rand::{RngCore, thread_rng};
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::{Path, PathBuf};
+
+use super::secure_nuke_database;
+
+/// Argon2id cost for newly keyed vaults. Vaults whose header is weaker than this
+/// are re-keyed on the next successful unlock.
+pub const VAULT_KDF_MEMORY_KIB: u32 = 64 * 1024;
+pub const VAULT_KDF_ITERATIONS: u32 = 3;
+pub const VAULT_KDF_PARALLELISM: u32 = 1;
+
+/// Minutes without user activity before an unlocked vault is closed; 0 disables it.
+pub const DEFAULT_AUTO_LOCK_MINUTES: u32 = 15;
+
+/// Unencrypted parameters needed to turn the passphrase back into the SQLCipher key.
+/// Nothing in here is secret.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct VaultHeader {
+    pub version: u32,
+    pub kdf: String,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub salt: String,
+}
+
+impl VaultHeader {
+    pub fn generate() -> Self {
+        let mut salt = [0u8; 16];
+        thread_rng().fill_bytes(&mut salt);
+        Self {
+            version: 1,
+            kdf: "argon2id".to_string(),
+            memory_kib: VAULT_KDF_MEMORY_KIB,
+            iterations: VAULT_KDF_ITERATIONS,
+            parallelism: VAULT_KDF_PARALLELISM,
+            salt: hex::encode(salt),
+        }
+    }
+
+    pub fn is_outdated(&self) -> bool {
+        self.kdf != "argon2id"
+            || self.memory_kib < VAULT_KDF_MEMORY_KIB
+            || self.iterations < VAULT_KDF_ITERATIONS
+    }
+
+    pub fn derive_bytes(&self, passphrase: &str, len: usize) -> Result<Vec<u8>, String> {
+        if self.kdf != "argon2id" {
+            return Err(format!("Unsupported vault KDF: {}", self.kdf));
+        }
+        let salt = hex::decode(&self.salt).map_err(|e| e.to_string())?;
+        let params = argon2::Params::new(self.memory_kib, self.iterations, self.parallelism, Some(len))
+            .map_err(|e| e.to_string())?;
+        let argon = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+
+        let mut out = vec![0u8; len];
+        argon.hash_password_into(passphrase.as_bytes(), &salt, &mut out).map_err(|e| e.to_string())?;
+        Ok(out)
+    }
+
+    /// Raw 256-bit key in SQLCipher's `x'...'` form, which skips its internal PBKDF2.
+    pub fn derive_key(&self, passphrase: &str) -> Result<String, String> {
+        Ok(raw_sqlcipher_key(&self.derive_bytes(passphrase, 32)?))
+    }
+
+    /// Key the local attachment store briefly used before [`load_or_create_attachment_key`].
+    /// It changes with every rekey, so it is only derived to migrate attachments off it.
+    /// Argon2 mixes the output length into its hash, so this run shares nothing with the
+    /// SQLCipher key.
+    pub fn derive_attachment_key(&self, passphrase: &str) -> Result<Vec<u8>, String> {
+        let okm = self.derive_bytes(passphrase, 64)?;
+        let mut key = vec![0u8; 32];
+        Hkdf::<Sha256>::new(None, &okm)
+            .expand(b"Entropy attachment key", &mut key)
+            .map_err(|e| e.to_string())?;
+        Ok(key)
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Self>, String> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&data).map(Some).map_err(|e| format!("Corrupt vault header: {}", e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let data = serde_json::to_string(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, data).map_err(|e| e.to_string())
+    }
+}
+
+pub fn raw_sqlcipher_key(key: &[u8]) -> String {
+    format!("x'{}'", hex::encode(key))
+}
+
+pub fn vault_header_path(db_path: &Path) -> PathBuf {
+    db_path.with_extension("header")
+}
+
+/// Header written before a rekey and promoted once the vault is confirmed to open with it.
+fn pending_header_path(db_path: &Path) -> PathBuf {
+    db_path.with_extension("header.pending")
+}
+
+/// Legacy vaults have no header and were keyed with the passphrase string itself.
+fn sqlcipher_key(passphrase: &str, header: Option<&VaultHeader>) -> Result<String, String> {
+    match header {
+        Some(h) => h.derive_key(passphrase),
+        None => Ok(passphrase.to_string()),
+    }
+}
+
+/// Opens the file and proves the key is right by reading the schema,
+/// since `PRAGMA key` itself never fails on a wrong key.
+pub(crate) fn open_with_key(db_path: &Path, key: &str) -> Result<Connection, String> {
+    let conn = Connection::open(db_path).map_err(|e| e.to_string())?;
+    if let Err(e) = conn.pragma_update(None, "key", key) {
+        return Err(format!("Failed to set encryption key: {}", e));
+    }
+    conn.query_row("SELECT count(*) FROM sqlite_master;", [], |r| r.get::<_, i64>(0))
+        .map_err(|_| "Incorrect passphrase".to_string())?;
+    Ok(conn)
+}
+
+/// Opens an existing vault with whatever KDF its header records. Never re-keys.
+pub fn open_vault(db_path: &Path, passphrase: &str) -> Result<Connection, String> {
+    let header = VaultHeader::load(&vault_header_path(db_path))?;
+    open_with_key(db_path, &sqlcipher_key(passphrase, header.as_ref())?)
+}
+
+/// Opens the vault for use, creating it with a fresh header if it does not exist yet
+/// and upgrading legacy or weak KDF parameters once the passphrase is proven correct.
+pub fn unlock_vault(db_path: &Path, passphrase: &str) -> Result<Connection, String> {
+    settle_pending_header(db_path, passphrase)?;
+    let header_path = vault_header_path(db_path);
+
+    let is_new = !db_path.exists() || std::fs::metadata(db_path).map(|m| m.len() == 0).unwrap_or(false);
+    if is_new && !header_path.exists() {
+        let header = VaultHeader::generate();
+        header.save(&header_path)?;
+        return open_with_key(db_path, &header.derive_key(passphrase)?);
+    }
+
+    let header = VaultHeader::load(&header_path)?;
+    let conn = open_with_key(db_path, &sqlcipher_key(passphrase, header.as_ref())?)?;
+    if header.as_ref().map(|h| h.is_outdated()).unwrap_or(true) {
+        // A failed upgrade leaves the vault on its old key, which still opens it.
+        if let Err(e) = rekey_vault(&conn, db_path, passphrase) {
+            eprintln!("Vault KDF upgrade failed, keeping the old key: {}", e);
+        }
+    }
+    Ok(conn)
+}
+
+/// Moves `conn` onto a freshly generated header for `passphrase`. The header is staged
+/// first so a crash between the rekey and the rename is recoverable.
+pub(crate) fn rekey_vault(conn: &Connection, db_path: &Path, passphrase: &str) -> Result<(), String> {
+    let header = VaultHeader::generate();
+    let key = header.derive_key(passphrase)?;
+    let pending_path = pending_header_path(db_path);
+    header.save(&pending_path)?;
+
+    if let Err(e) = conn.pragma_update(None, "rekey", &key) {
+        let _ = std::fs::remove_file(&pending_path);
+        return Err(e.to_string());
+    }
+    open_with_key(db_path, &key)?;
+    std::fs::rename(&pending_path, vault_header_path(db_path)).map_err(|e| e.to_string())
+}
+
+/// Resolves a header left staged by an interrupted rekey: promote it if it opens the
+/// vault, otherwise the rekey never happened and it is discarded.
+fn settle_pending_header(db_path: &Path, passphrase: &str) -> Result<(), String> {
+    let pending_path = pending_header_path(db_path);
+    let pending = match VaultHeader::load(&pending_path) {
+        Ok(Some(h)) => h,
+        _ => return Ok(()),
+    };
+    if open_with_key(db_path, &pending.derive_key(passphrase)?).is_ok() {
+        std::fs::rename(&pending_path, vault_header_path(db_path)).map_err(|e| e.to_string())?;
+    } else if open_vault(db_path, passphrase).is_ok() {
+        let _ = std::fs::remove_file(&pending_path);
+    }
+    Ok(())
+}
+
+/// Moves a fully prepared vault file into place as the live vault. The header is staged
+/// the same way as for a rekey, so a crash between the two renames is settled on unlock.
+pub fn install_vault(db_path: &Path, prepared_path: &Path, header: &VaultHeader) -> Result<(), String> {
+    let pending_path = pending_header_path(db_path);
+    header.save(&pending_path)?;
+    let _ = std::fs::remove_file(db_path.with_extension("db-journal"));
+    let _ = std::fs::remove_file(db_path.with_extension("db-wal"));
+    if let Err(e) = std::fs::rename(prepared_path, db_path) {
+        let _ = std::fs::remove_file(&pending_path);
+        return Err(e.to_string());
+    }
+    std::fs::rename(&pending_path, vault_header_path(db_path)).map_err(|e| e.to_string())
+}
+
+/// Copy of the vault taken before a passphrase change; only present while one is
+/// in flight or if the app died during it.
+pub fn rekey_backup_path(db_path: &Path) -> PathBuf {
+    db_path.with_extension("db.rekey")
+}
+
+/// Re-encrypts the open vault under `new_passphrase`. On any failure the vault is
+/// restored from the pre-rekey copy and reopened with `old_passphrase`.
+pub fn change_vault_passphrase(slot: &mut Option<Connection>, db_path: &Path, old_passphrase: &str, new_passphrase: &str) -> Result<(), String> {
+    let conn = slot.as_ref().ok_or_else(|| "Vault not initialized".to_string())?;
+    if new_passphrase.is_empty() {
+        return Err("New passphrase must not be empty".to_string());
+    }
+    open_vault(db_path, old_passphrase).map_err(|_| "Current passphrase is incorrect".to_string())?;
+
+    let backup_path = rekey_backup_path(db_path);
+    std::fs::copy(db_path, &backup_path).map_err(|e| format!("Failed to back up vault: {}", e))?;
+
+    if let Err(e) = rekey_vault(conn, db_path, new_passphrase) {
+        *slot = None;
+        let _ = std::fs::remove_file(pending_header_path(db_path));
+        restore_rekey_backup(db_path)?;
+        *slot = Some(open_vault(db_path, old_passphrase)?);
+        return Err(format!("Failed to change passphrase: {}", e));
+    }
+
+    let _ = secure_nuke_database(&backup_path);
+    Ok(())
+}
+
+/// Puts the pre-rekey copy back in place. Any journal left by the interrupted rekey
+/// belongs to the half-written file and must not be replayed onto the copy.
+pub fn restore_rekey_backup(db_path: &Path) -> Result<(), String> {
+    let backup_path = rekey_backup_path(db_path);
+    let _ = std::fs::remove_file(db_path.with_extension("db-journal"));
+    let _ = std::fs::remove_file(db_path.with_extension("db-wal"));
+    std::fs::copy(&backup_path, db_path).map_err(|e| format!("Failed to restore vault backup: {}", e))?;
+    let _ = secure_nuke_database(&backup_path);
+    Ok(())
+}
+
+/// Settles a passphrase change that was interrupted before it finished: whichever of
+/// the two files the passphrase opens is the one the user meant.
+pub fn recover_interrupted_rekey(db_path: &Path, passphrase: &str) -> Result<(), String> {
+    let backup_path = rekey_backup_path(db_path);
+    if !backup_path.exists() {
+        return Ok(());
+    }
+    settle_pending_header(db_path, passphrase)?;
+
+    let header = VaultHeader::load(&vault_header_path(db_path))?;
+    let key = sqlcipher_key(passphrase, header.as_ref())?;
+    if open_with_key(db_path, &key).is_ok() {
+        let _ = secure_nuke_database(&backup_path);
+    } else if open_with_key(&backup_path, &key).is_ok() {
+        restore_rekey_backup(db_path)?;
+    }
+    Ok(())
+}
+
+/// Where the replaced vault is kept while an import is being swapped in.
+pub fn rollback_path(db_path: &Path) -> PathBuf {
+    db_path.with_extension("db.rollback")
+}
+
+fn rollback_header_path(db_path: &Path) -> PathBuf {
+    db_path.with_extension("header.rollback")
+}
+
+/// Opens `path` with `key` and checks it is an intact vault this build can use:
+/// every page authenticates, the b-tree is sound and the schema is one we know.
+pub fn validate_vault_file(path: &Path, key: &str) -> Result<Connection, String> {
+    let conn = open_with_key(path, key)?;
+
+    let cipher_errors: Vec<String> = conn.prepare("PRAGMA cipher_integrity_check;")
+        .and_then(|mut s| s.query_map([], |r| r.get(0))?.collect())
+        .map_err(|e| e.to_string())?;
+    if !cipher_errors.is_empty() {
+        return Err(format!("Vault failed integrity check: {}", cipher_errors.join("; ")));
+    }
+    let quick_check: String = conn.query_row("PRAGMA quick_check;", [], |r| r.get(0)).map_err(|e| e.to_string())?;
+    if quick_check != "ok" {
+        return Err(format!("Vault failed integrity check: {}", quick_check));
+    }
+
+    let version = super::migrations::schema_version(&conn)?;
+    if version as usize > super::migrations::MIGRATIONS.len() {
+        return Err(format!("Vault schema v{} is newer than this build supports", version));
+    }
+    let vault_columns: i64 = conn.query_row("SELECT count(*) FROM pragma_table_info('vault') WHERE name IN ('key', 'value');", [], |r| r.get(0))
+        .map_err(|e| e.to_string())?;
+    if vault_columns != 2 {
+        return Err("File is not an Entropy vault".to_string());
+    }
+    Ok(conn)
+}
+
+/// Writes imported vault bytes to `dest` and validates them with `passphrase`. Vaults
+/// without a header or with weak KDF parameters are re-keyed onto a fresh one, which is
+/// returned. `dest` is wiped again if anything fails.
+pub fn prepare_vault_import(bytes: &[u8], header: Option<VaultHeader>, passphrase: &str, dest: &Path) -> Result<VaultHeader, String> {
+    let result = (|| {
+        let mut file = std::fs::File::create(dest).map_err(|e| e.to_string())?;
+        std::io::Write::write_all(&mut file, bytes).map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())?;
+        drop(file);
+
+        let conn = validate_vault_file(dest, &sqlcipher_key(passphrase, header.as_ref())?)?;
+        match header {
+            Some(h) if !h.is_outdated() => Ok(h),
+            _ => {
+                let fresh = VaultHeader::generate();
+                conn.pragma_update(None, "rekey", fresh.derive_key(passphrase)?).map_err(|e| e.to_string())?;
+                Ok(fresh)
+            }
+        }
+    })();
+    if result.is_err() {
+        let _ = secure_nuke_database(dest);
+    }
+    result
+}
+
+/// Swaps a prepared vault in for the live one and opens it. The previous vault is kept
+/// as a rollback copy until the new one has opened and migrated, and put back otherwise.
+/// The caller must have closed its connection to the live vault.
+pub fn replace_vault(db_path: &Path, prepared_path: &Path, header: &VaultHeader, passphrase: &str) -> Result<Connection, String> {
+    let rollback = rollback_path(db_path);
+    let header_path = vault_header_path(db_path);
+    if db_path.exists() {
+        std::fs::rename(db_path, &rollback).map_err(|e| format!("Failed to set aside current vault: {}", e))?;
+        if header_path.exists() {
+            let _ = std::fs::rename(&header_path, rollback_header_path(db_path));
+        }
+    }
+
+    let opened = install_vault(db_path, prepared_path, header)
+        .and_then(|_| unlock_vault(db_path, passphrase))
+        .and_then(|conn| super::types::init_database(&conn).map(|_| conn));
+
+    match opened {
+        Ok(conn) => {
+            let _ = secure_nuke_database(&rollback);
+            let _ = std::fs::remove_file(rollback_header_path(db_path));
+            Ok(conn)
+        }
+        Err(e) => {
+            roll_back_import(db_path)?;
+            Err(format!("Import failed, previous vault restored: {}", e))
+        }
+    }
+}
+
+fn roll_back_import(db_path: &Path) -> Result<(), String> {
+    let rollback = rollback_path(db_path);
+    let header_path = vault_header_path(db_path);
+    let _ = secure_nuke_database(db_path);
+    let _ = std::fs::remove_file(&header_path);
+    let _ = std::fs::remove_file(pending_header_path(db_path));
+    let _ = std::fs::remove_file(db_path.with_extension("db-journal"));
+    if rollback.exists() {
+        std::fs::rename(&rollback, db_path).map_err(|e| format!("Failed to restore previous vault: {}", e))?;
+        let _ = std::fs::rename(rollback_header_path(db_path), &header_path);
+    }
+    Ok(())
+}
+
+/// Settles an import that was interrupted mid-swap: keep the imported vault if the
+/// passphrase opens it, otherwise fall back to the one it replaced.
+pub fn recover_interrupted_import(db_path: &Path, passphrase: &str) -> Result<(), String> {
+    if !rollback_path(db_path).exists() {
+        return Ok(());
+    }
+    if !db_path.exists() {
+        return roll_back_import(db_path);
+    }
+    settle_pending_header(db_path, passphrase)?;
+    if open_vault(db_path, passphrase).is_ok() {
+        let _ = secure_nuke_database(&rollback_path(db_path));
+        let _ = std::fs::remove_file(rollback_header_path(db_path));
+        return Ok(());
+    }
+
+    let old_header = VaultHeader::load(&rollback_header_path(db_path))?;
+    if open_with_key(&rollback_path(db_path), &sqlcipher_key(passphrase, old_header.as_ref())?).is_ok() {
+        roll_back_import(db_path)?;
+    }
+    Ok(())
+}
+
+/// Key for the local attachment store, generated on first use and kept inside the
+/// encrypted vault, so rekeys and passphrase changes leave stored attachments readable.
+pub fn load_or_create_attachment_key(conn: &Connection) -> Result<Vec<u8>, String> {
+    let stored: Option<String> = conn.query_row("SELECT value FROM vault WHERE key = 'attachment_key';", [], |r| r.get(0))
+        .optional()
+        .map_err(|e| e.to_string())?;
+    if let Some(key) = stored {
+        return hex::decode(key).map_err(|e| e.to_string());
+    }
+    let mut key = vec![0u8; 32];
+    thread_rng().fill_bytes(&mut key);
+    conn.execute("INSERT INTO vault (key, value) VALUES ('attachment_key', ?1);", [hex::encode(&key)])
+        .map_err(|e| e.to_string())?;
+    Ok(key)
+}
+
+pub fn load_auto_lock_minutes(conn: &Connection) -> Result<u32, String> {
+    let value: Option<String> = conn.query_row("SELECT value FROM vault WHERE key = 'auto_lock_minutes';", [], |r| r.get(0))
+        .optional()
+        .map_err(|e| e.to_string())?;
+    Ok(value.and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_AUTO_LOCK_MINUTES))
+}
+
+pub fn save_auto_lock_minutes(conn: &Connection, minutes: u32) -> Result<(), String> {
+    conn.execute("INSERT OR REPLACE INTO vault (key, value) VALUES ('auto_lock_minutes', ?1);", [minutes.to_string()])
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
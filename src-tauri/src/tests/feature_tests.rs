@@ -77,4 +77,322 @@ mod tests {
              }
         }
     }
+
+    #[test]
+    fn test_vault_change_passphrase() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("vault.db");
+
+        let conn = unlock_vault(&db_path, "old pass").unwrap();
+        init_database(&conn).unwrap();
+        conn.execute("INSERT INTO vault (key, value) VALUES (?1, ?2)", ["test_key", "test_value"]).unwrap();
+        let mut slot = Some(conn);
+
+        // Wrong current passphrase leaves everything untouched
+        assert!(change_vault_passphrase(&mut slot, &db_path, "wrong", "new pass").is_err());
+        assert!(open_vault(&db_path, "old pass").is_ok());
+
+        change_vault_passphrase(&mut slot, &db_path, "old pass", "new pass").unwrap();
+        assert!(!rekey_backup_path(&db_path).exists());
+        assert!(open_vault(&db_path, "old pass").is_err());
+
+        // The live connection keeps working after the rekey
+        let val: String = slot.as_ref().unwrap().query_row("SELECT value FROM vault WHERE key = 'test_key'", [], |r| r.get(0)).unwrap();
+        assert_eq!(val, "test_value");
+        drop(slot);
+
+        let reopened = open_vault(&db_path, "new pass").unwrap();
+        let val: String = reopened.query_row("SELECT value FROM vault WHERE key = 'test_key'", [], |r| r.get(0)).unwrap();
+        assert_eq!(val, "test_value");
+    }
+
+    #[test]
+    fn test_interrupted_rekey_recovery() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("vault.db");
+        {
+            let conn = unlock_vault(&db_path, "old pass").unwrap();
+            init_database(&conn).unwrap();
+        }
+
+        // Backup taken, then the main file was left unreadable mid-rekey
+        std::fs::copy(&db_path, rekey_backup_path(&db_path)).unwrap();
+        std::fs::write(&db_path, "half written").unwrap();
+
+        recover_interrupted_rekey(&db_path, "old pass").unwrap();
+        assert!(!rekey_backup_path(&db_path).exists());
+        assert!(open_vault(&db_path, "old pass").is_ok());
+    }
+
+    #[test]
+    fn test_vault_kdf_header_and_upgrade() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("vault.db");
+
+        // Vault created before the KDF header existed: passphrase used as the key directly
+        {
+            let conn = Connection::open(&db_path).unwrap();
+            conn.pragma_update(None, "key", "legacy pass").unwrap();
+            init_database(&conn).unwrap();
+            conn.execute("INSERT INTO vault (key, value) VALUES (?1, ?2)", ["test_key", "test_value"]).unwrap();
+        }
+        assert!(!vault_header_path(&db_path).exists());
+
+        {
+            let conn = unlock_vault(&db_path, "legacy pass").unwrap();
+            let val: String = conn.query_row("SELECT value FROM vault WHERE key = 'test_key'", [], |r| r.get(0)).unwrap();
+            assert_eq!(val, "test_value");
+        }
+
+        // Upgraded to Argon2id: the bare passphrase no longer opens the file
+        let header = VaultHeader::load(&vault_header_path(&db_path)).unwrap().unwrap();
+        assert_eq!(header.kdf, "argon2id");
+        assert!(!header.is_outdated());
+        let raw = Connection::open(&db_path).unwrap();
+        raw.pragma_update(None, "key", "legacy pass").unwrap();
+        assert!(raw.query_row("SELECT count(*) FROM sqlite_master", [], |r| r.get::<_, i64>(0)).is_err());
+        assert!(open_vault(&db_path, "legacy pass").is_ok());
+        assert!(open_vault(&db_path, "wrong pass").is_err());
+
+        // Weaker parameters are replaced on the next unlock
+        {
+            let weak = VaultHeader { memory_kib: 1024, iterations: 1, ..VaultHeader::generate() };
+            let conn = open_vault(&db_path, "legacy pass").unwrap();
+            conn.pragma_update(None, "rekey", weak.derive_key("legacy pass").unwrap()).unwrap();
+            weak.save(&vault_header_path(&db_path)).unwrap();
+        }
+        unlock_vault(&db_path, "legacy pass").unwrap();
+        let upgraded = VaultHeader::load(&vault_header_path(&db_path)).unwrap().unwrap();
+        assert_eq!(upgraded.memory_kib, VAULT_KDF_MEMORY_KIB);
+        assert!(open_vault(&db_path, "legacy pass").is_ok());
+    }
+
+    #[test]
+    fn test_encrypted_backup_roundtrip() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("vault.db");
+        let backup_path = dir.path().join("vault.entropy-backup");
+
+        let conn = unlock_vault(&db_path, "live pass").unwrap();
+        init_database(&conn).unwrap();
+        conn.execute("INSERT INTO vault (key, value) VALUES (?1, ?2)", ["test_key", "test_value"]).unwrap();
+        export_backup(&conn, &db_path, &backup_path, "backup pass").unwrap();
+
+        let header = read_backup_header(&backup_path).unwrap();
+        assert_eq!(header.version, BACKUP_FORMAT_VERSION);
+        assert_eq!(header.schema_version, schema_version(&conn).unwrap());
+        assert!(!db_path.with_extension("db.export").exists());
+
+        // Wrong passphrase and tampering are rejected without leaving a partial vault behind
+        let restored_path = dir.path().join("restored.db");
+        assert!(restore_backup(&backup_path, &restored_path, "wrong pass", "new pass").is_err());
+        assert!(!restored_path.exists());
+
+        let mut bytes = std::fs::read(&backup_path).unwrap();
+        let tampered_path = dir.path().join("tampered.entropy-backup");
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+        std::fs::write(&tampered_path, &bytes).unwrap();
+        assert!(restore_backup(&tampered_path, &restored_path, "backup pass", "new pass").is_err());
+
+        bytes[last] ^= 0x01;
+        std::fs::write(&tampered_path, &bytes[..bytes.len() - 100]).unwrap();
+        assert!(restore_backup(&tampered_path, &restored_path, "backup pass", "new pass").is_err());
+        assert!(!restored_path.exists());
+
+        let vault_header = restore_backup(&backup_path, &restored_path, "backup pass", "new pass").unwrap();
+        let restored = Connection::open(&restored_path).unwrap();
+        restored.pragma_update(None, "key", vault_header.derive_key("new pass").unwrap()).unwrap();
+        let val: String = restored.query_row("SELECT value FROM vault WHERE key = 'test_key'", [], |r| r.get(0)).unwrap();
+        assert_eq!(val, "test_value");
+    }
+
+    #[test]
+    fn test_vault_import_validation_and_rollback() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("vault.db");
+        let import_path = dir.path().join("vault.db.import");
+
+        {
+            let conn = unlock_vault(&db_path, "current pass").unwrap();
+            init_database(&conn).unwrap();
+            conn.execute("INSERT INTO vault (key, value) VALUES (?1, ?2)", ["owner", "current"]).unwrap();
+        }
+
+        // Exported vault from another device, still in the pre-header format
+        let source_path = dir.path().join("source.db");
+        {
+            let conn = Connection::open(&source_path).unwrap();
+            conn.pragma_update(None, "key", "other pass").unwrap();
+            init_database(&conn).unwrap();
+            conn.execute("INSERT INTO vault (key, value) VALUES (?1, ?2)", ["owner", "imported"]).unwrap();
+        }
+        let bytes = std::fs::read(&source_path).unwrap();
+
+        // Truncated file and wrong passphrase are rejected before the live vault is touched
+        assert!(prepare_vault_import(&bytes[..bytes.len() / 2], None, "other pass", &import_path).is_err());
+        assert!(prepare_vault_import(&bytes, None, "wrong pass", &import_path).is_err());
+        assert!(!import_path.exists());
+        assert!(open_vault(&db_path, "current pass").is_ok());
+
+        let header = prepare_vault_import(&bytes, None, "other pass", &import_path).unwrap();
+        let conn = replace_vault(&db_path, &import_path, &header, "other pass").unwrap();
+        let owner: String = conn.query_row("SELECT value FROM vault WHERE key = 'owner'", [], |r| r.get(0)).unwrap();
+        assert_eq!(owner, "imported");
+        assert!(!rollback_path(&db_path).exists());
+        drop(conn);
+
+        // A vault that validates but then fails to open is swapped back out
+        prepare_vault_import(&bytes, None, "other pass", &import_path).unwrap();
+        let mismatched = VaultHeader::generate();
+        assert!(replace_vault(&db_path, &import_path, &mismatched, "other pass").is_err());
+        let conn = open_vault(&db_path, "other pass").unwrap();
+        let owner: String = conn.query_row("SELECT value FROM vault WHERE key = 'owner'", [], |r| r.get(0)).unwrap();
+        assert_eq!(owner, "imported");
+    }
+
+    #[test]
+    fn test_auto_backup_rotation() {
+        let dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+        let db_path = dir.path().join("vault.db");
+
+        let conn = unlock_vault(&db_path, "live pass").unwrap();
+        init_database(&conn).unwrap();
+        let config = AutoBackupConfig {
+            directory: backup_dir.path().to_string_lossy().to_string(),
+            interval_minutes: 60,
+            keep: 2,
+            key: BackupKey::derive("backup pass").unwrap(),
+        };
+        save_auto_backup_config(&conn, Some(&config)).unwrap();
+        assert!(load_auto_backup_config(&conn).unwrap().is_some());
+        std::fs::write(backup_dir.path().join("manual.entropy-backup"), "keep me").unwrap();
+
+        let status = load_auto_backup_status(&conn).unwrap();
+        assert!(status.is_due(&config, 0));
+
+        let mut written = Vec::new();
+        for i in 0..3 {
+            conn.execute("INSERT INTO vault (key, value) VALUES ('generation', ?1) ON CONFLICT(key) DO UPDATE SET value = ?1", [i.to_string()]).unwrap();
+            let (snapshot, header) = snapshot_vault(&conn, &db_path, &config.key).unwrap();
+            written.push(write_rotated_backup(&snapshot, &header, &config).unwrap());
+            assert!(!snapshot.exists());
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+
+        // Oldest generation pruned, unrelated files left alone
+        assert!(!written[0].exists());
+        assert!(written[1].exists() && written[2].exists());
+        assert!(backup_dir.path().join("manual.entropy-backup").exists());
+
+        let restored_path = dir.path().join("restored.db");
+        let vault_header = restore_backup(&written[2], &restored_path, "backup pass", "live pass").unwrap();
+        let restored = Connection::open(&restored_path).unwrap();
+        restored.pragma_update(None, "key", vault_header.derive_key("live pass").unwrap()).unwrap();
+        let generation: String = restored.query_row("SELECT value FROM vault WHERE key = 'generation'", [], |r| r.get(0)).unwrap();
+        assert_eq!(generation, "2");
+
+        let done = AutoBackupStatus { last_attempt: Some(1_000), ..Default::default() };
+        assert!(!done.is_due(&config, 1_000 + 59 * 60 * 1000));
+        assert!(done.is_due(&config, 1_000 + 60 * 60 * 1000));
+    }
+
+    #[test]
+    fn test_plaintext_secret_migration() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("entropy_vault_salt.secret"), "salt-value-1234").unwrap();
+        std::fs::write(dir.path().join("keyring_ok.secret"), "goes to keyring").unwrap();
+
+        let keyring = std::cell::RefCell::new(Vec::new());
+        migrate_plaintext_secrets(dir.path(), |k, v| {
+            keyring.borrow_mut().push((k.to_string(), v.to_string()));
+            k == "keyring_ok"
+        }).unwrap();
+
+        // Plaintext files are gone; only the one the keyring refused went to the fallback store
+        assert!(!dir.path().join("entropy_vault_salt.secret").exists());
+        assert!(!dir.path().join("keyring_ok.secret").exists());
+        assert_eq!(keyring.borrow().len(), 2);
+        assert_eq!(load_fallback_secret(dir.path(), "entropy_vault_salt").unwrap().as_deref(), Some("salt-value-1234"));
+        assert_eq!(load_fallback_secret(dir.path(), "keyring_ok").unwrap(), None);
+
+        let raw = std::fs::read_to_string(secret_store_path(dir.path())).unwrap();
+        assert!(!raw.contains("salt-value-1234"));
+
+        remove_fallback_secret(dir.path(), "entropy_vault_salt").unwrap();
+        assert_eq!(load_fallback_secret(dir.path(), "entropy_vault_salt").unwrap(), None);
+    }
+
+    #[test]
+    fn test_duress_decoy_vault() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("vault.db");
+        let slot = decoy_slot_path(&db_path);
+
+        {
+            let conn = unlock_vault(&db_path, "real pass").unwrap();
+            init_database(&conn).unwrap();
+            conn.execute("INSERT INTO vault (key, value) VALUES (?1, ?2)", ["owner", "real"]).unwrap();
+        }
+        ensure_decoy_slot(&db_path).unwrap();
+        assert!(slot.exists());
+        assert!(open_decoy_vault(&db_path, "duress pass").unwrap().is_none());
+
+        // Decoy alongside the real vault: no extra files, and the slot size stays page-aligned
+        let files_before = std::fs::read_dir(dir.path()).unwrap().count();
+        create_decoy_vault(&db_path, "duress pass", false).unwrap();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), files_before);
+        assert_eq!(std::fs::metadata(&slot).unwrap().len() % 4096, 0);
+
+        let (decoy, file) = open_decoy_vault(&db_path, "duress pass").unwrap().unwrap();
+        assert_eq!(file, VaultFile::Decoy(db_path.clone()));
+        decoy.execute("INSERT INTO vault (key, value) VALUES (?1, ?2)", ["owner", "decoy"]).unwrap();
+        assert!(open_decoy_vault(&db_path, "real pass").unwrap().is_none());
+
+        // Commands run against the decoy see the decoy: its file and header reopen it
+        assert!(file.check_passphrase("duress pass").is_ok());
+        assert!(file.check_passphrase("real pass").is_err());
+        let exported = open_with_key(&file.path(), &file.header().unwrap().unwrap().derive_key("duress pass").unwrap()).unwrap();
+        let owner: String = exported.query_row("SELECT value FROM vault WHERE key = 'owner'", [], |r| r.get(0)).unwrap();
+        assert_eq!(owner, "decoy");
+        drop(exported);
+
+        let mut slot_conn = Some(decoy);
+        change_decoy_passphrase(&mut slot_conn, &db_path, "duress pass", "new duress").unwrap();
+        let owner: String = slot_conn.as_ref().unwrap().query_row("SELECT value FROM vault WHERE key = 'owner'", [], |r| r.get(0)).unwrap();
+        assert_eq!(owner, "decoy");
+        drop(slot_conn);
+        assert!(open_decoy_vault(&db_path, "duress pass").unwrap().is_none());
+        assert!(open_decoy_vault(&db_path, "new duress").unwrap().is_some());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), files_before);
+
+        let conn = open_vault(&db_path, "real pass").unwrap();
+        let owner: String = conn.query_row("SELECT value FROM vault WHERE key = 'owner'", [], |r| r.get(0)).unwrap();
+        assert_eq!(owner, "real");
+        drop(conn);
+
+        // Wiping decoy replaces the real vault and then opens like a normal one
+        create_decoy_vault(&db_path, "duress pass", true).unwrap();
+        let salt_before: Vec<u8> = std::fs::read(&slot).unwrap()[..16].to_vec();
+        let (decoy, file) = open_decoy_vault(&db_path, "duress pass").unwrap().unwrap();
+        assert_eq!(file, VaultFile::Main(db_path.clone()));
+        decoy.execute("INSERT INTO vault (key, value) VALUES (?1, ?2)", ["owner", "decoy"]).unwrap();
+        drop(decoy);
+        assert!(has_discarded_vault(&db_path));
+        // Installed under a fresh header rather than the salt it shipped with
+        let header = VaultHeader::load(&vault_header_path(&db_path)).unwrap().unwrap();
+        assert_ne!(header.salt, hex::encode(&salt_before));
+        assert!(open_with_key(&db_path, &header.derive_key("duress pass").unwrap()).is_ok());
+        shred_discarded_vault(&db_path);
+        assert!(!has_discarded_vault(&db_path));
+
+        assert!(open_vault(&db_path, "real pass").is_err());
+        assert!(open_decoy_vault(&db_path, "real pass").unwrap().is_none());
+        let conn = unlock_vault(&db_path, "duress pass").unwrap();
+        let owner: String = conn.query_row("SELECT value FROM vault WHERE key = 'owner'", [], |r| r.get(0)).unwrap();
+        assert_eq!(owner, "decoy");
+        assert!(slot.exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), files_before);
+    }
 }
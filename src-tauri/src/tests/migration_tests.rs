@@ -0,0 +1,90 @@
+use crate::protocol::*;
+use crate::protocol::migrations::{apply_migrations, Migration, MIGRATIONS};
+use rusqlite::Connection;
+use serde_json::json;
+use tempfile::tempdir;
+
+/// The schema `init_database` produced before vaults carried a version.
+fn create_unversioned_vault(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE vault (key TEXT PRIMARY KEY, value TEXT);
+         CREATE TABLE pending_messages (id TEXT PRIMARY KEY, recipient_hash TEXT, body TEXT, timestamp INTEGER, retries INTEGER);
+         CREATE TABLE groups (group_id TEXT PRIMARY KEY, state TEXT);
+         CREATE TABLE messages (
+            id TEXT PRIMARY KEY,
+            peer_hash TEXT,
+            timestamp INTEGER,
+            content TEXT,
+            sender_hash TEXT,
+            type TEXT,
+            is_mine INTEGER,
+            status TEXT,
+            reply_to_id TEXT,
+            attachment_json TEXT
+         );
+         CREATE INDEX idx_messages_peer ON messages(peer_hash);
+         INSERT INTO messages (id, peer_hash, timestamp, content, sender_hash, type, is_mine, status)
+            VALUES ('old1', 'bobhash', 42, 'Written before migrations existed', 'bobhash', 'text', 0, 'read');
+         INSERT INTO vault (key, value) VALUES ('test_key', 'test_value');"
+    ).unwrap();
+}
+
+#[test]
+fn test_upgrade_unversioned_vault() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("vault.db");
+
+    {
+        let conn = Connection::open(&db_path).unwrap();
+        create_unversioned_vault(&conn);
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+    }
+
+    {
+        let conn = Connection::open(&db_path).unwrap();
+        init_database(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap() as usize, MIGRATIONS.len());
+
+        // Existing rows survive and pick up the new features
+        let val: String = conn.query_row("SELECT value FROM vault WHERE key = 'test_key'", [], |r| r.get(0)).unwrap();
+        assert_eq!(val, "test_value");
+        let hits = search_messages(&conn, "migrations").unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0]["id"], "old1");
+
+        save_decrypted_message(&conn, "bobhash", &json!({ "id": "new1", "timestamp": 43, "content": "after upgrade" })).unwrap();
+        assert_eq!(get_messages(&conn, "bobhash", None, None).unwrap()["messages"].as_array().unwrap().len(), 2);
+    }
+
+    // Reopening an up-to-date vault is a no-op
+    let conn = Connection::open(&db_path).unwrap();
+    init_database(&conn).unwrap();
+    assert_eq!(schema_version(&conn).unwrap() as usize, MIGRATIONS.len());
+}
+
+#[test]
+fn test_failed_migration_rolls_back() {
+    fn create_table(tx: &rusqlite::Transaction) -> rusqlite::Result<()> {
+        tx.execute("CREATE TABLE first (id INTEGER);", []).map(|_| ())
+    }
+    fn half_applied(tx: &rusqlite::Transaction) -> rusqlite::Result<()> {
+        tx.execute("CREATE TABLE second (id INTEGER);", [])?;
+        tx.execute("INSERT INTO missing_table VALUES (1);", []).map(|_| ())
+    }
+    let steps: [Migration; 2] = [create_table, half_applied];
+
+    let conn = Connection::open_in_memory().unwrap();
+    let err = apply_migrations(&conn, &steps).unwrap_err();
+    assert!(err.contains("v2"));
+
+    assert_eq!(schema_version(&conn).unwrap(), 1);
+    let second_exists = conn.prepare("SELECT 1 FROM sqlite_master WHERE name = 'second'").unwrap().exists([]).unwrap();
+    assert!(!second_exists);
+}
+
+#[test]
+fn test_newer_vault_is_rejected() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.pragma_update(None, "user_version", MIGRATIONS.len() as u32 + 1).unwrap();
+    assert!(init_database(&conn).is_err());
+}
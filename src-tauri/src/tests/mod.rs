@@ -1,3 +1,4 @@
 pub mod protocol_tests;
 pub mod feature_tests;
 pub mod new_tests;
+pub mod migration_tests;
@@ -384,3 +384,301 @@ fn test_pre_key_replenishment_cap() {
     assert_eq!(identity.pre_keys[0].key_id, 31);
     assert_eq!(identity.pre_keys[99].key_id, 130);
 }
+
+#[test]
+fn test_simultaneous_initiation_resolution() {
+    let conn_alice = setup_memory_db();
+    let conn_bob = setup_memory_db();
+
+    let id_alice = generate_new_identity();
+    id_alice.save_to_db(&conn_alice).unwrap();
+    let id_bob = generate_new_identity();
+    id_bob.save_to_db(&conn_bob).unwrap();
+
+    let bundle_for = |id: &ProtocolIdentity| serde_json::json!({
+        "identityKey": id.identity_keys.public_key,
+        "pq_identityKey": id.identity_keys.pq_public_key,
+        "signedPreKey": {
+            "keyId": id.signed_pre_key.key_id,
+            "publicKey": id.signed_pre_key.public_key,
+            "signature": id.signed_pre_key.signature,
+            "pq_publicKey": id.signed_pre_key.pq_public_key
+        },
+        "preKeys": []
+    });
+
+    // Both sides fetch a bundle and initiate before either PreKey message arrives.
+    establish_outbound_session(&conn_alice, "bob", &bundle_for(&id_bob)).unwrap();
+    establish_outbound_session(&conn_bob, "alice", &bundle_for(&id_alice)).unwrap();
+
+    let a1 = ratchet_encrypt(&conn_alice, "bob", "Alice 1").unwrap();
+    let a2 = ratchet_encrypt(&conn_alice, "bob", "Alice 2").unwrap();
+    let a3 = ratchet_encrypt(&conn_alice, "bob", "Alice 3").unwrap();
+    let b1 = ratchet_encrypt(&conn_bob, "alice", "Bob 1").unwrap();
+    let b2 = ratchet_encrypt(&conn_bob, "alice", "Bob 2").unwrap();
+    let b3 = ratchet_encrypt(&conn_bob, "alice", "Bob 3").unwrap();
+
+    // Delivered out of order: a later message can be the first one to arrive.
+    assert_eq!(ratchet_decrypt(&conn_alice, "bob", &b2).unwrap(), "Bob 2");
+    assert_eq!(ratchet_decrypt(&conn_alice, "bob", &b1).unwrap(), "Bob 1");
+    assert_eq!(ratchet_decrypt(&conn_bob, "alice", &a2).unwrap(), "Alice 2");
+    assert_eq!(ratchet_decrypt(&conn_bob, "alice", &a1).unwrap(), "Alice 1");
+
+    // Exactly one side keeps the losing session around for in-flight messages.
+    let alice_crossed = SessionState::load_crossed_from_db(&conn_alice, "bob").unwrap().is_some();
+    let bob_crossed = SessionState::load_crossed_from_db(&conn_bob, "alice").unwrap().is_some();
+    assert!(alice_crossed ^ bob_crossed);
+    let (winner, winner_peer, loser, loser_peer) = if alice_crossed {
+        (&conn_alice, "bob", &conn_bob, "alice")
+    } else {
+        (&conn_bob, "alice", &conn_alice, "bob")
+    };
+
+    // Both sides converge on the winning session.
+    let w1 = ratchet_encrypt(winner, winner_peer, "Winner 1").unwrap();
+    assert_eq!(ratchet_decrypt(loser, loser_peer, &w1).unwrap(), "Winner 1");
+    let l1 = ratchet_encrypt(loser, loser_peer, "Loser 1").unwrap();
+    assert_eq!(ratchet_decrypt(winner, winner_peer, &l1).unwrap(), "Loser 1");
+
+    // Messages from the losing session that were still in flight are not lost.
+    let straggler = if alice_crossed { (&b3, "Bob 3") } else { (&a3, "Alice 3") };
+    assert_eq!(ratchet_decrypt(winner, winner_peer, straggler.0).unwrap(), straggler.1);
+    assert!(SessionState::load_crossed_from_db(winner, winner_peer).unwrap().is_some());
+    assert!(SessionState::load_crossed_from_db(loser, loser_peer).unwrap().is_none());
+
+    // The crossed session goes once its grace period has passed.
+    let mut main = SessionState::load_from_db(winner, winner_peer).unwrap().unwrap();
+    assert!(main.crossed_until.is_some());
+    main.crossed_until = Some(0);
+    main.save_to_db(winner, winner_peer).unwrap();
+    let l2 = ratchet_encrypt(loser, loser_peer, "Loser 2").unwrap();
+    assert_eq!(ratchet_decrypt(winner, winner_peer, &l2).unwrap(), "Loser 2");
+    assert!(SessionState::load_crossed_from_db(winner, winner_peer).unwrap().is_none());
+    assert!(SessionState::load_from_db(winner, winner_peer).unwrap().unwrap().crossed_until.is_none());
+}
+
+#[test]
+fn test_tampered_messages_leave_session_unchanged() {
+    let conn_alice = setup_memory_db();
+    let conn_bob = setup_memory_db();
+
+    let id_alice = generate_new_identity();
+    id_alice.save_to_db(&conn_alice).unwrap();
+    let id_bob = generate_new_identity();
+    id_bob.save_to_db(&conn_bob).unwrap();
+
+    let bob_bundle = serde_json::json!({
+        "identityKey": id_bob.identity_keys.public_key,
+        "signedPreKey": {
+            "keyId": id_bob.signed_pre_key.key_id,
+            "publicKey": id_bob.signed_pre_key.public_key,
+            "signature": id_bob.signed_pre_key.signature,
+            "pq_publicKey": id_bob.signed_pre_key.pq_public_key
+        },
+        "preKeys": [],
+        "pq_identityKey": id_bob.identity_keys.pq_public_key
+    });
+    establish_outbound_session(&conn_alice, "bob", &bob_bundle).unwrap();
+
+    let tamper = |msg: &serde_json::Value, field: &str| {
+        let mut bytes = decode_b64(msg[field].as_str().unwrap()).unwrap();
+        bytes[0] ^= 0x01;
+        let mut tampered = msg.clone();
+        tampered[field] = serde_json::Value::String(encode_b64(&bytes));
+        tampered
+    };
+    let session_row = |conn: &Connection| -> Option<String> {
+        conn.query_row("SELECT value FROM vault WHERE key = 'session_alice'", [], |r| r.get(0)).ok()
+    };
+
+    // A forged PreKey message must not create a responder session.
+    let msg0 = ratchet_encrypt(&conn_alice, "bob", "Init").unwrap();
+    assert!(ratchet_decrypt(&conn_bob, "alice", &tamper(&msg0, "body")).is_err());
+    assert!(session_row(&conn_bob).is_none());
+    assert_eq!(ratchet_decrypt(&conn_bob, "alice", &msg0).unwrap(), "Init");
+
+    // Leave msg1 undelivered so Bob holds a skipped key for it.
+    let msg1 = ratchet_encrypt(&conn_alice, "bob", "Message 1").unwrap();
+    let msg2 = ratchet_encrypt(&conn_alice, "bob", "Message 2").unwrap();
+    let msg3 = ratchet_encrypt(&conn_alice, "bob", "Message 3").unwrap();
+    assert_eq!(ratchet_decrypt(&conn_bob, "alice", &msg2).unwrap(), "Message 2");
+
+    let before = session_row(&conn_bob).unwrap();
+    for forged in [
+        tamper(&msg1, "body"),
+        tamper(&msg1, "nonce"),
+        tamper(&msg3, "body"),
+        tamper(&msg3, "header_enc"),
+        tamper(&msg3, "header_nonce"),
+        msg2.clone(),
+    ] {
+        assert!(ratchet_decrypt(&conn_bob, "alice", &forged).is_err());
+        assert_eq!(session_row(&conn_bob).unwrap(), before);
+    }
+
+    assert_eq!(ratchet_decrypt(&conn_bob, "alice", &msg1).unwrap(), "Message 1");
+    assert_eq!(ratchet_decrypt(&conn_bob, "alice", &msg3).unwrap(), "Message 3");
+    let state = SessionState::load_from_db(&conn_bob, "alice").unwrap().unwrap();
+    assert!(state.skipped_message_keys.is_empty());
+}
+
+fn bundle_of(id: &ProtocolIdentity) -> serde_json::Value {
+    serde_json::json!({
+        "identityKey": id.identity_keys.public_key,
+        "signedPreKey": {
+            "keyId": id.signed_pre_key.key_id,
+            "publicKey": id.signed_pre_key.public_key,
+            "signature": id.signed_pre_key.signature,
+            "pq_publicKey": id.signed_pre_key.pq_public_key
+        },
+        "preKeys": [],
+        "pq_identityKey": id.identity_keys.pq_public_key
+    })
+}
+
+fn paired_sessions(bob_conns: &[&Connection]) -> Connection {
+    let conn_alice = setup_memory_db();
+    generate_new_identity().save_to_db(&conn_alice).unwrap();
+    let id_bob = generate_new_identity();
+    for conn in bob_conns {
+        id_bob.save_to_db(conn).unwrap();
+    }
+    establish_outbound_session(&conn_alice, "bob", &bundle_of(&id_bob)).unwrap();
+    conn_alice
+}
+
+#[test]
+fn test_session_cache_write_behind() {
+    let conn_bob = setup_memory_db();
+    let conn_alice = paired_sessions(&[&conn_bob]);
+    let msgs: Vec<_> = (0..5).map(|i| ratchet_encrypt(&conn_alice, "bob", &format!("m{}", i)).unwrap()).collect();
+
+    let mut sessions = SessionCache::new(SESSION_CACHE_CAPACITY);
+    assert_eq!(ratchet_decrypt_cached(&conn_bob, &mut sessions, "alice", &msgs[0]).unwrap(), "m0");
+    assert!(SessionState::load_from_db(&conn_bob, "alice").unwrap().is_none());
+    assert_eq!(sessions.dirty_len(), 1);
+
+    // Later messages build on the cached copy, and a forged one does not disturb it
+    assert_eq!(ratchet_decrypt_cached(&conn_bob, &mut sessions, "alice", &msgs[2]).unwrap(), "m2");
+    let mut forged = msgs[3].clone();
+    forged["body"] = serde_json::Value::String(encode_b64(b"forged"));
+    assert!(ratchet_decrypt_cached(&conn_bob, &mut sessions, "alice", &forged).is_err());
+    assert_eq!(ratchet_decrypt_cached(&conn_bob, &mut sessions, "alice", &msgs[3]).unwrap(), "m3");
+
+    sessions.flush(&conn_bob).unwrap();
+    assert_eq!(sessions.dirty_len(), 0);
+    let stored = SessionState::load_from_db(&conn_bob, "alice").unwrap().unwrap();
+    assert_eq!(stored.skipped_message_keys.len(), 1);
+
+    // Sending writes through at once
+    ratchet_encrypt_cached(&conn_bob, &mut sessions, "alice", "reply").unwrap();
+    assert_eq!(sessions.dirty_len(), 0);
+    assert_eq!(SessionState::load_from_db(&conn_bob, "alice").unwrap().unwrap().sequence_number_send, 1);
+
+    // Evicting a dirty session persists it
+    let mut tiny = SessionCache::new(1);
+    sessions.clear();
+    assert_eq!(ratchet_decrypt_cached(&conn_bob, &mut tiny, "alice", &msgs[1]).unwrap(), "m1");
+    assert!(tiny.get(&conn_bob, "nobody").unwrap().is_none());
+    tiny.put(&conn_bob, "carol", SessionState::default()).unwrap();
+    assert_eq!(tiny.dirty_len(), 1);
+    assert!(SessionState::load_from_db(&conn_bob, "alice").unwrap().unwrap().skipped_message_keys.is_empty());
+    assert_eq!(ratchet_decrypt_cached(&conn_bob, &mut tiny, "alice", &msgs[4]).unwrap(), "m4");
+}
+
+#[test]
+fn test_decrypt_batch() {
+    let conn_bob = setup_memory_db();
+    let conn_alice = paired_sessions(&[&conn_bob]);
+    let id_bob = ProtocolIdentity::load_from_db(&conn_bob).unwrap().unwrap();
+    let alice_ik = ProtocolIdentity::load_from_db(&conn_alice).unwrap().unwrap().identity_keys.public_key;
+
+    let conn_carol = setup_memory_db();
+    generate_new_identity().save_to_db(&conn_carol).unwrap();
+    establish_outbound_session(&conn_carol, "bob", &bundle_of(&id_bob)).unwrap();
+
+    // Alice seals hers, so only the envelope says who she is
+    let bob_sk = StaticSecret::from(<[u8; 32]>::try_from(decode_b64(&id_bob.identity_keys.private_key).unwrap()).unwrap());
+    let seal = |msg: serde_json::Value| seal_sender(msg, &alice_ik, &X25519PublicKey::from(&bob_sk), &id_bob.identity_keys.pq_public_key).unwrap();
+    let a: Vec<_> = (0..3).map(|i| ratchet_encrypt(&conn_alice, "bob", &format!("a{}", i)).unwrap()).collect();
+    let c: Vec<_> = (0..2).map(|i| ratchet_encrypt(&conn_carol, "bob", &format!("c{}", i)).unwrap()).collect();
+
+    let mut forged = c[1].clone();
+    forged["body"] = serde_json::Value::String(encode_b64(b"forged"));
+    let group = serde_json::json!({ "type": "group_message_v2", "groupId": "g", "sender": "dave", "body": "x" });
+    let item = |sender: Option<&str>, envelope: serde_json::Value| BatchItem { sender: sender.map(str::to_string), envelope };
+    let items = vec![
+        item(None, seal(a[0].clone())),
+        item(Some("carol"), serde_json::json!({ "sender": "carol", "message": c[0] })),
+        item(None, seal(a[1].clone())),
+        item(Some("carol"), forged),
+        item(None, serde_json::json!({ "sealed": true, "ephemeral_public": "garbage" })),
+        item(None, group.clone()),
+        item(None, seal(a[2].clone())),
+        item(Some("carol"), c[1].clone()),
+    ];
+
+    let mut sessions = SessionCache::new(SESSION_CACHE_CAPACITY);
+    let results = decrypt_batch(&conn_bob, &mut sessions, items).unwrap();
+    let plaintexts: Vec<_> = results.iter().map(|r| r.plaintext.as_deref()).collect();
+    assert_eq!(plaintexts, [Some("a0"), Some("c0"), Some("a1"), None, None, None, Some("a2"), Some("c1")]);
+    assert_eq!(results[0].sender.as_deref(), Some(alice_ik.as_str()));
+    assert!(results[3].error.is_some() && results[4].error.is_some());
+    assert_eq!(results[5].message, Some(group));
+    assert!(results[5].error.is_none());
+
+    // Each sender's run was persisted with the batch
+    assert_eq!(sessions.dirty_len(), 0);
+    assert_eq!(SessionState::load_from_db(&conn_bob, &alice_ik).unwrap().unwrap().sequence_number_recv, 3);
+    let next = ratchet_encrypt(&conn_carol, "bob", "c2").unwrap();
+    assert_eq!(ratchet_decrypt(&conn_bob, "carol", &next).unwrap(), "c2");
+}
+
+/// Drains a 10k-message offline backlog with and without the session cache. Run with
+/// `cargo test --release bench_backlog_drain -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_backlog_drain() {
+    const BACKLOG: usize = 10_000;
+
+    // On-disk encrypted vaults, so the cost of rewriting sessions is what users pay
+    let dir = std::env::temp_dir().join(format!("entropy-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let open_vault_db = |name: &str| {
+        let conn = Connection::open(dir.join(name)).unwrap();
+        conn.pragma_update(None, "key", raw_sqlcipher_key(&[7u8; 32])).unwrap();
+        init_database(&conn).unwrap();
+        conn
+    };
+    let conn_uncached = open_vault_db("uncached.db");
+    let conn_cached = open_vault_db("cached.db");
+    let conn_alice = paired_sessions(&[&conn_uncached, &conn_cached]);
+    let msgs: Vec<_> = (0..BACKLOG).map(|i| ratchet_encrypt(&conn_alice, "bob", &format!("Backlog message {}", i)).unwrap()).collect();
+
+    let start = std::time::Instant::now();
+    for msg in &msgs {
+        ratchet_decrypt(&conn_uncached, "alice", msg).unwrap();
+    }
+    let uncached = start.elapsed();
+
+    let start = std::time::Instant::now();
+    let mut sessions = SessionCache::new(SESSION_CACHE_CAPACITY);
+    for msg in &msgs {
+        ratchet_decrypt_cached(&conn_cached, &mut sessions, "alice", msg).unwrap();
+        if sessions.dirty_len() >= SESSION_FLUSH_BATCH {
+            sessions.flush(&conn_cached).unwrap();
+        }
+    }
+    sessions.flush(&conn_cached).unwrap();
+    let cached = start.elapsed();
+
+    let rate = |d: std::time::Duration| BACKLOG as f64 / d.as_secs_f64();
+    println!("uncached: {:?} ({:.0} msg/s)", uncached, rate(uncached));
+    println!("cached:   {:?} ({:.0} msg/s)", cached, rate(cached));
+    assert_eq!(
+        serde_json::to_string(&SessionState::load_from_db(&conn_uncached, "alice").unwrap()).unwrap(),
+        serde_json::to_string(&SessionState::load_from_db(&conn_cached, "alice").unwrap()).unwrap()
+    );
+    drop((conn_uncached, conn_cached));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -1,12 +1,14 @@
 import { get } from 'svelte/store';
-import { userStore } from '../stores/user';
+import { userStore, type BackupStatus } from '../stores/user';
 import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
 import { signalManager } from '../signal_manager';
 import { network } from '../network';
 import { minePoW, initCrypto } from '../crypto';
-import { statusTimeouts, setOnlineStatus, startHeartbeat } from './contacts';
+import { statusTimeouts, setOnlineStatus, startHeartbeat, stopHeartbeat } from './contacts';
 import { broadcastProfile } from './contacts';
-import { secureLoad, secureStore, initVault, vaultLoad, vaultSave } from '../secure_storage';
+import { watchMessageUpdates } from './messaging';
+import { secureLoad, secureStore, initVault, vaultLoad, vaultSave, changeVaultPassphrase, lockVault } from '../secure_storage';
 import { attachmentStore } from '../attachment_store';
 import type { Chat } from '../types';
 
@@ -20,7 +22,7 @@ export const initApp = async (password: string) => {
     const attemptsKey = salt ? `entropy_failed_attempts_${salt.slice(0, 8)}` : 'entropy_failed_attempts_global';
 
     try {
-        await initVault(password);
+        if (await initVault(password)) clearLocalData();
     } catch (e: any) {
         console.error("Vault init failed:", e);
         const errorMsg = e.message || e.toString();
@@ -48,10 +50,11 @@ export const initApp = async (password: string) => {
         let sessionToken: string | null = null;
 
         const saved = await vaultLoad(`entropy_chats_${idHash}`);
-        const vaultKey = await signalManager.getLocalEncryptionKey(password);
+        const vaultKey = await signalManager.getLocalEncryptionKey();
         if (vaultKey) {
             let storageKey = vaultKey;
             attachmentStore.setEncryptionKey(storageKey);
+            await migrateLegacyAttachments(password, storageKey);
 
             if (saved) {
                 try {
@@ -78,6 +81,9 @@ export const initApp = async (password: string) => {
         userStore.update(s => ({ ...s, identityHash: idHash, chats, myAlias, myPfp, sessionToken, authError: null }));
         network.connect();
         startHeartbeat();
+        loadBackupStatus().catch(() => { });
+        watchVaultLock();
+        watchMessageUpdates();
 
         const serverUrl = get(userStore).relayUrl;
         try { await signalManager.ensureKeysUploaded(serverUrl); } catch (e) { }
@@ -87,18 +93,42 @@ export const initApp = async (password: string) => {
     }
 };
 
+const ATTACHMENT_KDF_MARKER = 'entropy_attachment_kdf';
+
+/**
+ * Moves attachments onto the key kept in the vault, once: from the PBKDF2 key when no
+ * marker is set, or from the vault header key when the marker says `argon2id`.
+ */
+const migrateLegacyAttachments = async (password: string, key: Uint8Array) => {
+    const marker = await vaultLoad(ATTACHMENT_KDF_MARKER);
+    if (marker === 'vault') return;
+    try {
+        const oldKey = marker === 'argon2id'
+            ? await signalManager.getHeaderEncryptionKey(password)
+            : await signalManager.getLegacyEncryptionKey(password);
+        if (oldKey) await attachmentStore.reencrypt(oldKey, key);
+        await vaultSave(ATTACHMENT_KDF_MARKER, 'vault');
+    } catch (e) {
+        console.error("Attachment key migration failed, will retry on next unlock:", e);
+    }
+};
+
+const clearLocalData = () => {
+    const keys = [];
+    for (let i = 0; i < localStorage.length; i++) {
+        const k = localStorage.key(i);
+        if (k && (k.startsWith('entropy_') || k.startsWith('signal_'))) keys.push(k);
+    }
+    keys.forEach(k => localStorage.removeItem(k));
+};
+
 const handleFailedAttempt = (key: string) => {
     const attempts = parseInt(localStorage.getItem(key) || "0") + 1;
     localStorage.setItem(key, attempts.toString());
 
     if (attempts >= 10) {
         invoke('nuclear_reset').catch(() => { });
-        const keys = [];
-        for (let i = 0; i < localStorage.length; i++) {
-            const k = localStorage.key(i);
-            if (k && (k.startsWith('entropy_') || k.startsWith('signal_'))) keys.push(k);
-        }
-        keys.forEach(k => localStorage.removeItem(k));
+        clearLocalData();
         userStore.update(s => ({ ...s, authError: "Vault wiped after 10 failed attempts." }));
     } else {
         userStore.update(s => ({ ...s, authError: `Wrong password. Attempts: ${attempts}/10` }));
@@ -129,15 +159,18 @@ export const createIdentity = async (password: string) => {
 
     if (idHash) {
         try {
-            let vaultKey = await signalManager.getLocalEncryptionKey(password);
+            let vaultKey = await signalManager.getLocalEncryptionKey();
             if (vaultKey) {
                 attachmentStore.setEncryptionKey(vaultKey);
+                await vaultSave(ATTACHMENT_KDF_MARKER, 'vault');
             }
             userStore.update(s => ({ ...s, identityHash: idHash }));
 
             console.debug("Connecting to network...");
             network.connect();
             startHeartbeat();
+            watchVaultLock();
+            watchMessageUpdates();
 
             console.debug("Uploading keys to server...");
             await signalManager.ensureKeysUploaded(get(userStore).relayUrl);
@@ -150,6 +183,11 @@ export const createIdentity = async (password: string) => {
     }
 };
 
+// Attachments are keyed from inside the vault, so they need nothing when the passphrase changes.
+export const changePassphrase = async (oldPassword: string, newPassword: string) => {
+    await changeVaultPassphrase(oldPassword, newPassword);
+};
+
 export const authenticate = async (identityHash: string) => {
     if (isAuthInProgress) return;
     isAuthInProgress = true;
@@ -206,6 +244,7 @@ export const burnAccount = async (serverUrl: string) => {
     if (confirm("DANGER: This will permanently purge your account from the server AND your local device. This cannot be undone. Are you absolutely sure?")) {
         const success = await signalManager.remoteBurn(serverUrl);
         if (success) {
+            await goOffline();
             window.location.reload();
         } else {
             alert("Forensic burn failed. The server might be unreachable.");
@@ -213,21 +252,23 @@ export const burnAccount = async (serverUrl: string) => {
     }
 };
 
+// Paths chosen in native dialogs come back as opaque tokens that Rust resolves itself.
+type PickedFile = { token: string; path: string };
+
 export const exportVault = async () => {
     try {
-        const { save } = await import('@tauri-apps/plugin-dialog');
-        const bytes = await invoke('protocol_export_vault') as number[];
-        const uint8 = new Uint8Array(bytes);
-
-        const filePath = await save({
-            filters: [{ name: 'Entropy Vault', extensions: ['db'] }],
-            defaultPath: 'entropy_backup.db'
-        });
-
-        if (filePath) {
-            await invoke('protocol_save_vault_to_path', { path: filePath, bytes: Array.from(uint8) });
-            alert("Vault exported successfully to: " + filePath);
-        }
+        const picked = await invoke('fs_pick_save_file', {
+            fileName: 'entropy.entropy-backup',
+            filterName: 'Entropy Backup',
+            extension: 'entropy-backup'
+        }) as PickedFile | null;
+        if (!picked) return;
+
+        const backupPassphrase = prompt("Choose a passphrase for this backup. It is needed to restore it and is independent of your login password.");
+        if (!backupPassphrase) return;
+
+        await invoke('protocol_export_backup', { token: picked.token, backupPassphrase });
+        alert("Vault exported successfully to: " + picked.path);
     } catch (e) {
         console.error("Export failed:", e);
         alert("Export failed: " + e);
@@ -238,21 +279,105 @@ export const importVault = async () => {
     if (!confirm("DANGER: Importing a vault will overwrite your current local data. This will purge all existing chats on this device. Continue?")) return;
 
     try {
-        const { open } = await import('@tauri-apps/plugin-dialog');
-        const file = await open({
-            multiple: false,
-            filters: [{ name: 'Entropy Vault', extensions: ['db'] }]
-        });
-
-        if (file) {
-            const path = typeof file === 'string' ? file : (file as any).path;
-            const bytes = await invoke('protocol_read_vault_from_path', { path }) as number[];
-            await invoke('protocol_import_vault', { bytes });
-            alert("Vault imported. The application will now restart.");
-            window.location.reload();
+        const picked = await invoke('fs_pick_open_file', {
+            filterName: 'Entropy Backup',
+            extension: 'entropy-backup'
+        }) as PickedFile | null;
+        if (!picked) return;
+
+        const token = picked.token;
+        const info = await invoke('protocol_inspect_backup', { token }) as { createdAt: number };
+        const backupPassphrase = prompt(`Backup from ${new Date(info.createdAt).toLocaleString()}. Enter its backup passphrase:`);
+        if (!backupPassphrase) return;
+        const vaultPassphrase = prompt("Enter the password you will use to unlock Entropy on this device:");
+        if (!vaultPassphrase) return;
+
+        await invoke('protocol_import_backup', { token, backupPassphrase, vaultPassphrase });
+        if (!(await secureLoad('entropy_vault_salt'))) {
+            await secureStore('entropy_vault_salt', crypto.randomUUID());
         }
+        alert("Vault imported. The application will now restart.");
+        window.location.reload();
     } catch (e) {
         console.error("Import failed:", e);
         alert("Import failed: " + e);
     }
 };
+
+// Resolves to false if the user backed out of the directory or passphrase prompt.
+export const configureAutoBackup = async (intervalMinutes: number, keep: number) => {
+    const directory = await invoke('fs_pick_directory') as PickedFile | null;
+    if (!directory) return false;
+
+    const backupPassphrase = prompt("Choose a passphrase for automatic backups. It is needed to restore them.");
+    if (!backupPassphrase) return false;
+    await invoke('protocol_configure_auto_backup', { directoryToken: directory.token, intervalMinutes, keep, backupPassphrase });
+    await runBackupNow();
+    return true;
+};
+
+export const disableAutoBackup = async () => {
+    await invoke('protocol_disable_auto_backup');
+    userStore.update(s => ({ ...s, backupStatus: null }));
+};
+
+export const runBackupNow = async () => {
+    const status = await invoke('protocol_run_auto_backup') as BackupStatus | null;
+    if (status) userStore.update(s => ({ ...s, backupStatus: status }));
+};
+
+let backupWatchStarted = false;
+
+// Resolves to the configured interval in minutes, or null while automatic backups are off.
+export const loadBackupStatus = async () => {
+    const res = await invoke('protocol_get_auto_backup_status') as { enabled: boolean; intervalMinutes: number | null; status: BackupStatus };
+    userStore.update(s => ({ ...s, backupStatus: res.enabled ? res.status : null }));
+    if (!backupWatchStarted) {
+        backupWatchStarted = true;
+        listen('backup-status', (event) => {
+            userStore.update(s => ({ ...s, backupStatus: event.payload as BackupStatus }));
+        }).catch(() => { backupWatchStarted = false; });
+    }
+    return res.enabled ? res.intervalMinutes : null;
+};
+
+let lockWatchStarted = false;
+let lastActivityReport = 0;
+
+const reportActivity = () => {
+    const now = Date.now();
+    if (now - lastActivityReport < 30_000) return;
+    lastActivityReport = now;
+    invoke('vault_report_activity').catch(() => { });
+};
+
+// Keys derived from the passphrase live in this page, so a locked vault is followed by
+// a reload that drops them and brings back the unlock screen.
+const watchVaultLock = () => {
+    if (lockWatchStarted) return;
+    lockWatchStarted = true;
+    ['keydown', 'pointerdown', 'wheel'].forEach(evt => window.addEventListener(evt, reportActivity, { passive: true }));
+    listen('vault-locked', async () => {
+        await goOffline();
+        window.location.reload();
+    }).catch(() => { });
+};
+
+// The native connection outlives a reload, so it is closed before the keys go away.
+const goOffline = async () => {
+    await stopHeartbeat();
+    await network.disconnect();
+};
+
+export const lockNow = async () => {
+    await goOffline();
+    await lockVault();
+};
+
+export const getAutoLockMinutes = async (): Promise<number> => {
+    return await invoke('vault_get_auto_lock');
+};
+
+export const setAutoLockMinutes = async (minutes: number) => {
+    await invoke('vault_set_auto_lock', { minutes });
+};
@@ -4,10 +4,14 @@ import { signalManager } from '../signal_manager';
 import { network } from '../network';
 import { minePoW } from '../crypto';
 import { bulkDelete, sendReceipt } from './message_utils';
+import { attachmentStore } from '../attachment_store';
 import type { PrivacySettings } from '../types';
+import { invoke } from '@tauri-apps/api/core';
+import { listen, type UnlistenFn } from '@tauri-apps/api/event';
 
 export const statusTimeouts: Record<string, any> = {};
 let heartbeatInterval: any = null;
+let expiryListener: Promise<UnlistenFn> | null = null;
 
 export const markOnline = (peerHash: string) => {
     if (statusTimeouts[peerHash]) clearTimeout(statusTimeouts[peerHash]);
@@ -51,25 +55,37 @@ export const startHeartbeat = () => {
         }
     }, 30000);
 
-    // Disappearing messages cleanup (every 3s)
-    setInterval(() => {
-        const state = get(userStore);
-        const now = Date.now();
-
-        Object.keys(state.chats).forEach(h => {
-            const chat = state.chats[h];
-            if (chat.disappearingTimer && chat.disappearingTimer > 0) {
-                const expiryTime = chat.disappearingTimer * 1000;
-                const expiredIds = chat.messages
-                    .filter(m => !m.isStarred && (now - m.timestamp) >= expiryTime)
-                    .map(m => m.id);
-
-                if (expiredIds.length > 0) {
-                    bulkDelete(h, expiredIds);
-                }
-            }
+    // Rust purges expired rows from the vault and reports them here. Anything that expired
+    // while nothing was listening is still queued natively and replayed once we are.
+    if (!expiryListener) {
+        expiryListener = listen('messages-expired', (event) => {
+            wipeExpired(event.payload as ExpiredMessage[]).catch(() => { });
         });
-    }, 3000);
+        expiryListener.then(
+            () => invoke<ExpiredMessage[]>('protocol_pending_expired').then(wipeExpired).catch(() => { }),
+            () => { expiryListener = null; }
+        );
+    }
+};
+
+type ExpiredMessage = { id: string; peerHash: string };
+
+// Only acknowledged once the blobs are gone, so an interrupted wipe is retried next time.
+export const wipeExpired = async (expired: ExpiredMessage[]) => {
+    if (expired.length === 0) return;
+    await Promise.all(expired.map(m => attachmentStore.wipe(m.id)));
+    const byPeer: Record<string, string[]> = {};
+    expired.forEach(m => (byPeer[m.peerHash] ||= []).push(m.id));
+    Object.entries(byPeer).forEach(([h, ids]) => bulkDelete(h, ids));
+    await invoke('protocol_ack_expired', { ids: expired.map(m => m.id) });
+};
+
+export const stopHeartbeat = async () => {
+    if (heartbeatInterval) clearInterval(heartbeatInterval);
+    heartbeatInterval = null;
+    const listener = expiryListener;
+    expiryListener = null;
+    if (listener) (await listener.catch(() => null))?.();
 };
 
 export const updateMyProfile = (alias: string, pfp: string | null) => {
@@ -134,6 +150,7 @@ export const setDisappearingTimer = async (peerHash: string, seconds: number | n
         if (s.chats[peerHash]) s.chats[peerHash].disappearingTimer = seconds || undefined;
         return { ...s, chats: { ...s.chats } };
     });
+    invoke('protocol_set_disappearing_timer', { peerHash, seconds }).catch(() => { });
 
     const syncMsg = { type: 'disappearing_sync', seconds };
     try {
@@ -269,6 +286,8 @@ export const startChat = (peerHash: string, alias?: string) => {
         if (unreadIds.length > 0) {
             s.chats[peerHash].unreadCount = 0;
             sendReceipt(peerHash, unreadIds, 'read');
+            // Starts read-triggered disappearing timers in the vault
+            invoke('protocol_mark_read', { peerHash, ids: unreadIds }).catch(() => { });
         }
 
         return { ...s, activeChatHash: peerHash, chats: { ...s.chats } };
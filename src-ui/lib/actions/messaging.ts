@@ -5,7 +5,8 @@ import { network } from '../network';
 import { attachmentStore } from '../attachment_store';
 import { callManager } from '../call_manager';
 import { invoke } from '@tauri-apps/api/core';
-import type { Message, ServerMessage } from '../types';
+import { listen } from '@tauri-apps/api/event';
+import type { BatchDecryptResult, Message, ServerMessage } from '../types';
 import { parseLinkPreview, fromHex } from '../utils';
 import { fromBase64, toBase64 } from '../crypto';
 import { markOnline, setOnlineStatus, broadcastProfile, statusTimeouts } from './contacts';
@@ -15,6 +16,8 @@ export { addMessage, bulkDelete, deleteMessage, downloadAttachment, sendReceipt
 
 export const setReplyingTo = (msg: Message | null) => userStore.update(s => ({ ...s, replyingTo: msg }));
 
+let updateWatchStarted = false;
+
 const incomingChunksProgress = new Map<string, { total: number, received: number, chunks: Uint8Array[], fileName: string, fileType: string, bundle: any }>();
 
 export const sendMessage = async (destId: string, content: string) => {
@@ -239,6 +242,71 @@ export const sendVoiceNote = async (destId: string, audioBlob: Blob) => {
     addMessage(destId, msg);
 };
 
+const applyMessageUpdate = (updated: any) => {
+    userStore.update(s => {
+        const msg = s.chats[updated.peerHash]?.messages.find(m => m.id === updated.id);
+        if (msg) {
+            msg.content = updated.content;
+            msg.editedAt = updated.editedAt ?? undefined;
+            msg.isDeleted = updated.isDeleted;
+            msg.reactions = updated.reactions;
+            if (updated.isDeleted) msg.attachment = undefined;
+        }
+        return { ...s, chats: { ...s.chats } };
+    });
+};
+
+/** Edits, deletes and reactions from peers are applied by the backend as they decrypt. */
+export const watchMessageUpdates = () => {
+    if (updateWatchStarted) return;
+    updateWatchStarted = true;
+    listen<any>('message-updated', (event) => applyMessageUpdate(event.payload));
+};
+
+const sendControl = async (peerHash: string, control: any) => {
+    const state = get(userStore);
+    if (!state.identityHash) return;
+    try {
+        // Applied locally first: the backend refuses edits and deletes that are not ours
+        const updated = await invoke('protocol_apply_control_message', { peerHash, control });
+        applyMessageUpdate(updated);
+
+        if (state.chats[peerHash]?.isGroup) {
+            const ciphertext = await signalManager.groupEncrypt(peerHash, JSON.stringify(control));
+            network.sendJSON({
+                type: 'group_message_v2',
+                groupId: peerHash,
+                sender: state.identityHash,
+                body: ciphertext.body,
+                nonce: ciphertext.nonce,
+                key_id: ciphertext.key_id,
+                id: crypto.randomUUID()
+            });
+        } else {
+            const ciphertext = await signalManager.encrypt(peerHash, JSON.stringify(control), state.relayUrl);
+            network.sendBinary(peerHash, new TextEncoder().encode(JSON.stringify(ciphertext)));
+        }
+    } catch (e) {
+        console.error("Control send failed", e);
+    }
+};
+
+export const editMessage = (peerHash: string, msgId: string, content: string) =>
+    sendControl(peerHash, { type: 'edit', targetId: msgId, content, timestamp: Date.now() });
+
+export const deleteForEveryone = (peerHash: string, msgId: string) =>
+    sendControl(peerHash, { type: 'delete', targetId: msgId, timestamp: Date.now() });
+
+/** An empty emoji takes our reaction back. */
+export const reactToMessage = (peerHash: string, msgId: string, emoji: string) =>
+    sendControl(peerHash, { type: 'react', targetId: msgId, emoji });
+
+export const getEditHistory = (messageId: string): Promise<{ content: string; editedAt: number | null }[]> =>
+    invoke('protocol_get_edit_history', { messageId });
+
+/** Pairwise payloads are bare JSON; older peers wrap theirs as `{ s, m }`. */
+const plaintextOf = (result: any): string => typeof result.m === 'string' ? result.m : JSON.stringify(result);
+
 const processPlaintext = async (senderHash: string, plaintext: string, groupId?: string, msgId?: string, replyToIn?: any) => {
     const state = get(userStore);
     if (state.blockedHashes.includes(senderHash)) return;
@@ -257,6 +325,9 @@ const processPlaintext = async (senderHash: string, plaintext: string, groupId?:
         if (parsed.replyTo) replyTo = parsed.replyTo;
         if (parsed.linkPreview) linkPreview = parsed.linkPreview;
 
+        // Already applied by the backend when it decrypted them
+        if (parsed.type === 'edit' || parsed.type === 'delete' || parsed.type === 'react') return;
+
         if (parsed.type === 'group_invite' || parsed.type === 'group_invite_v2') {
             userStore.update(s => {
                 if (!s.chats[parsed.groupId]) {
@@ -418,6 +489,7 @@ const processPlaintext = async (senderHash: string, plaintext: string, groupId?:
                 if (s.chats[senderHash]) s.chats[senderHash].disappearingTimer = parsed.seconds || undefined;
                 return { ...s, chats: { ...s.chats } };
             });
+            invoke('protocol_set_disappearing_timer', { peerHash: senderHash, seconds: parsed.seconds || null }).catch(() => { });
             return;
         } else if (parsed.type === 'call_log') {
             const msg: Message = {
@@ -485,6 +557,38 @@ const processPlaintext = async (senderHash: string, plaintext: string, groupId?:
     }
 };
 
+export const handleIncomingBatch = async (payloads: ServerMessage[]) => {
+    const state = get(userStore);
+    if (!state.identityHash || payloads.length === 0) return;
+
+    let results: BatchDecryptResult[];
+    try {
+        results = await signalManager.decryptBatch(payloads.map(p => ({ sender: p.sender, envelope: p })));
+    } catch (e) {
+        console.error("Batch decrypt failed, falling back to single messages:", e);
+        for (const p of payloads) await handleIncomingMessage(p);
+        return;
+    }
+
+    for (let i = 0; i < results.length; i++) {
+        const r = results[i];
+        if (r.plaintext) {
+            try {
+                const result = JSON.parse(r.plaintext);
+                if (result && (result.m || result.type)) {
+                    await processPlaintext(result.s || r.sender!, plaintextOf(result), undefined, undefined, undefined);
+                }
+            } catch (e) { }
+        } else if (r.message) {
+            // Group messages come back unsealed for the group path
+            await handleIncomingMessage(r.message);
+        } else {
+            // Unknown senders and identity changes need the single-message path
+            await handleIncomingMessage(payloads[i]);
+        }
+    }
+};
+
 export const handleIncomingMessage = async (payload: Uint8Array | ServerMessage) => {
     try {
         const state = get(userStore);
@@ -558,7 +662,7 @@ export const handleIncomingMessage = async (payload: Uint8Array | ServerMessage)
         }
 
         if (result && (result.m || result.type)) {
-            await processPlaintext(result.s || senderHash, result.m, undefined, undefined, undefined);
+            await processPlaintext(result.s || senderHash, plaintextOf(result), undefined, undefined, undefined);
         }
     } catch (e) { }
 };
@@ -68,6 +68,56 @@ export class AttachmentStore {
         });
     }
 
+    async reencrypt(oldKey: Uint8Array, newKey: Uint8Array): Promise<void> {
+        if (!this.db) await this.init();
+        const entries = await new Promise<[IDBValidKey, Uint8Array][]>((resolve, reject) => {
+            const out: [IDBValidKey, Uint8Array][] = [];
+            const transaction = this.db!.transaction([this.storeName], 'readonly');
+            const request = transaction.objectStore(this.storeName).openCursor();
+            request.onsuccess = (e: any) => {
+                const cursor = e.target.result;
+                if (!cursor) {
+                    resolve(out);
+                    return;
+                }
+                out.push([cursor.key, cursor.value]);
+                cursor.continue();
+            };
+            request.onerror = (e) => reject(e);
+        });
+
+        for (const [id, data] of entries) {
+            const plain = await decryptBinary(data, oldKey);
+            if (!plain) continue;
+            const finalData = await encryptBinary(plain, newKey);
+            await new Promise<void>((resolve, reject) => {
+                const transaction = this.db!.transaction([this.storeName], 'readwrite');
+                const request = transaction.objectStore(this.storeName).put(finalData, id);
+                request.onsuccess = () => resolve();
+                request.onerror = (e) => reject(e);
+            });
+        }
+        this.encryptionKey = newKey;
+    }
+
+    // Overwrites the stored ciphertext with random bytes before deleting it. The browser may
+    // still keep old pages around; the media key in the vault is what is shredded for sure.
+    async wipe(id: string): Promise<void> {
+        if (!this.db) await this.init();
+        await new Promise<void>((resolve, reject) => {
+            const transaction = this.db!.transaction([this.storeName], 'readwrite');
+            const store = transaction.objectStore(this.storeName);
+            const request = store.get(id);
+            request.onsuccess = (e: any) => {
+                const data = e.target.result as Uint8Array | undefined;
+                if (data) store.put(crypto.getRandomValues(new Uint8Array(data.byteLength)), id);
+            };
+            transaction.oncomplete = () => resolve();
+            transaction.onerror = (e) => reject(e);
+        });
+        await this.delete(id);
+    }
+
     async delete(id: string): Promise<void> {
         if (!this.db) await this.init();
         return new Promise((resolve, reject) => {
@@ -14,6 +14,16 @@ export const deriveVaultKey = async (password: string, salt: string): Promise<Ui
     return await invoke('crypto_pbkdf2', { password, salt });
 };
 
+/** Attachment key kept inside the unlocked vault. */
+export const getAttachmentKey = async (): Promise<Uint8Array> => {
+    return new Uint8Array(await invoke<number[]>('crypto_attachment_key'));
+};
+
+/** The attachment key derived from the vault header, used before `getAttachmentKey`. */
+export const deriveAttachmentKey = async (passphrase: string): Promise<Uint8Array> => {
+    return new Uint8Array(await invoke<number[]>('crypto_derive_attachment_key', { passphrase }));
+};
+
 export const encryptWithKey = async (plaintext: string, key: Uint8Array): Promise<string> => {
     const encoder = new TextEncoder();
     return await invoke('crypto_encrypt', { key, plaintext: Array.from(encoder.encode(plaintext)) });
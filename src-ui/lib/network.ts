@@ -1,15 +1,17 @@
-import { invoke } from '@tauri-apps/api/core';
+import { Channel, invoke } from '@tauri-apps/api/core';
 import { listen } from '@tauri-apps/api/event';
-import type { ServerMessage } from './types';
+import type { NetworkStatus, ServerMessage, TorBootstrap } from './types';
+import { routingProxy } from './utils';
 
 export class NetworkLayer {
     private url: string = "";
-    private retryCount = 0;
-    private maxRetries = 5;
+    private supervising = false;
     private isAuthenticated = false;
     private messageQueue: { type: 'json' | 'binary'; data: any; recipient?: string; isVolatile?: boolean }[] = [];
     private heartbeatInterval: any;
     private isConnected = false;
+    private queuedBatch: ServerMessage[] = [];
+    private queuedDrain: Promise<void> | null = null;
 
 
     private userStoreModule: any = null;
@@ -24,19 +26,42 @@ export class NetworkLayer {
             this.handleMessage(event.payload as string);
         });
 
+        listen('network-latency', (event) => {
+            const { ms } = event.payload as { ms: number };
+            this.userStoreModule?.userStore.update((s: any) => ({ ...s, latencyMs: ms }));
+        });
+
+        listen('tor-bootstrap', (event) => {
+            const { progress } = event.payload as TorBootstrap;
+            this.userStoreModule?.userStore.update((s: any) => ({ ...s, torBootstrap: progress }));
+        });
+
+        // Fallback for when no binary channel was registered.
         listen('network-bin', (event) => {
             this.handleBinaryMessage(new Uint8Array(event.payload as number[]));
         });
 
+        // The native side reconnects on its own; this only mirrors its state.
         listen('network-status', (event) => {
-            if (event.payload === 'disconnected') {
-                this.onDisconnect();
+            const status = event.payload as NetworkStatus;
+            if (status.state === 'connected') {
+                this.isConnected = true;
+                this.onConnect();
+            } else if (status.state === 'disconnected') {
+                if (this.isConnected) this.onDisconnect();
+            } else if (status.state === 'auth_failed') {
+                this.onAuthFailed();
+            } else if (status.state === 'connecting') {
+                this.userStoreModule?.userStore.update((s: any) => ({
+                    ...s,
+                    connectionStatus: s.connectionStatus === 'mining' ? s.connectionStatus : 'connecting'
+                }));
             }
         });
     }
 
     async connect() {
-        if (this.isConnected) return;
+        if (this.supervising) return;
 
         const { get } = await import('svelte/store');
         if (this.userStoreModule) {
@@ -44,39 +69,42 @@ export class NetworkLayer {
             this.url = state.relayUrl.replace('http', 'ws') + '/ws';
             const bearerToken = state.sessionToken;
 
-            let proxyUrl = undefined;
-            if (state.privacySettings.routingMode !== 'direct') {
-                proxyUrl = state.privacySettings.proxyUrl;
-                if (state.privacySettings.routingMode === 'tor') {
-                    proxyUrl = 'socks5://127.0.0.1:9050';
-                }
-            }
-
-            console.log(`Commanding native connection to ${this.url} (Proxy: ${proxyUrl || 'none'})...`);
+            this.supervising = true;
             try {
-                await invoke('connect_network', { relayUrl: this.url, bearerToken, proxyUrl });
-                this.isConnected = true;
-                this.onConnect();
+                const proxyUrl = await routingProxy();
+                console.log(`Commanding native connection to ${this.url} (Proxy: ${proxyUrl || 'none'})...`);
+                // Tor circuits are slow to answer; allow longer before a link counts as dead.
+                const keepalive = state.privacySettings.routingMode === 'tor'
+                    ? { pingIntervalSecs: 30, pingTimeoutSecs: 45 }
+                    : {};
+                // Binary frames arrive as raw ArrayBuffers instead of JSON number arrays.
+                const binaryChannel = new Channel<ArrayBuffer>();
+                binaryChannel.onmessage = (buf) => this.handleBinaryMessage(new Uint8Array(buf));
+                await invoke('set_stream_isolation', { enabled: true, account: state.identityHash ?? null });
+                await invoke('connect_network', { relayUrl: this.url, bearerToken, proxyUrl, ...keepalive, binaryChannel });
             } catch (e) {
+                this.supervising = false;
                 console.error("Native connection failed:", e);
-                this.retry();
             }
         }
     }
 
-    private stabilityTimer: any = null;
+    // Stops the native supervisor and drops anything still waiting to go out.
+    async disconnect() {
+        this.supervising = false;
+        this.messageQueue = [];
+        this.queuedBatch = [];
+        if (this.isConnected) this.onDisconnect();
+        try {
+            await invoke('disconnect_network');
+        } catch (e) {
+            console.error("Native disconnect failed:", e);
+        }
+    }
 
     private onConnect() {
         console.log('Native network layer connected');
 
-
-
-        if (this.stabilityTimer) clearTimeout(this.stabilityTimer);
-        this.stabilityTimer = setTimeout(() => {
-            console.log("Connection stabilized. Resetting retry count.");
-            this.retryCount = 0;
-        }, 5000);
-
         if (this.userStoreModule) {
             this.userStoreModule.userStore.update((s: any) => ({ ...s, isConnected: true }));
 
@@ -95,12 +123,6 @@ export class NetworkLayer {
     private onDisconnect() {
         console.log('Native network layer disconnected');
 
-
-        if (this.stabilityTimer) {
-            clearTimeout(this.stabilityTimer);
-            this.stabilityTimer = null;
-        }
-
         this.isConnected = false;
         this.isAuthenticated = false;
         this.stopHeartbeat();
@@ -109,16 +131,27 @@ export class NetworkLayer {
             this.userStoreModule.userStore.update((s: any) => ({
                 ...s,
                 isConnected: false,
-                connectionStatus: 'disconnected'
+                connectionStatus: 'disconnected',
+                latencyMs: null
             }));
         }
-        this.retry();
     }
 
-    private retry() {
-        if (this.retryCount < this.maxRetries) {
-            this.retryCount++;
-            setTimeout(() => this.connect(), 2000 * this.retryCount);
+    // The native side has already dropped the rejected token; mine a fresh one.
+    private onAuthFailed() {
+        console.warn("Authentication failed (Token probably expired). Clearing session and re-mining...");
+        this.isAuthenticated = false;
+        if (this.userStoreModule) {
+            this.userStoreModule.userStore.update((s: any) => ({ ...s, sessionToken: null, connectionStatus: 'mining' }));
+            if (this.logicStoreModule) {
+                this.logicStoreModule.resetAuthStatus();
+                import('svelte/store').then(({ get }) => {
+                    const state = get(this.userStoreModule.userStore) as any;
+                    if (state.identityHash) {
+                        this.logicStoreModule.authenticate(state.identityHash);
+                    }
+                });
+            }
         }
     }
 
@@ -189,23 +222,9 @@ export class NetworkLayer {
             return;
         }
 
+        // An `auth_failed` error also arrives as a `network-status` event and is handled there.
         if (msg.type === 'error') {
             console.error("Server error:", msg.message);
-            if (msg.code === 'auth_failed') {
-                console.warn("Authentication failed (Token probably expired). Clearing session and re-mining...");
-                if (this.userStoreModule) {
-                    this.userStoreModule.userStore.update((s: any) => ({ ...s, sessionToken: null, connectionStatus: 'mining' }));
-                    if (this.logicStoreModule) {
-                        this.logicStoreModule.resetAuthStatus();
-                        import('svelte/store').then(({ get }) => {
-                            const state = get(this.userStoreModule.userStore) as any;
-                            if (state.identityHash) {
-                                this.logicStoreModule.authenticate(state.identityHash);
-                            }
-                        });
-                    }
-                }
-            }
             return;
         }
 
@@ -215,13 +234,24 @@ export class NetworkLayer {
         }
 
         if (msg.type === 'queued_message') {
-            if (this.logicStoreModule) await this.logicStoreModule.handleIncomingMessage(msg.payload);
+            this.queuedBatch.push(msg.payload);
+            if (!this.queuedDrain) this.queuedDrain = this.drainQueued();
             return;
         }
 
         if (this.logicStoreModule) await this.logicStoreModule.handleIncomingMessage(msg);
     }
 
+    // The offline queue arrives as a burst of single messages; decrypt it in batches.
+    private async drainQueued() {
+        await new Promise(resolve => setTimeout(resolve, 50));
+        while (this.queuedBatch.length > 0) {
+            const batch = this.queuedBatch.splice(0, 500);
+            if (this.logicStoreModule) await this.logicStoreModule.handleIncomingBatch(batch);
+        }
+        this.queuedDrain = null;
+    }
+
     private flushQueue() {
         let sentCount = 0;
         while (this.messageQueue.length > 0 && sentCount < 10) {
@@ -299,11 +329,6 @@ export class NetworkLayer {
         });
     }
 
-    disconnect() {
-        this.stopHeartbeat();
-        this.isConnected = false;
-        this.isAuthenticated = false;
-    }
 }
 
 export const network = new NetworkLayer();
@@ -36,9 +36,35 @@ export const secureLoad = async (key: string): Promise<string | null> => {
 };
 
 
-export const initVault = async (passphrase: string): Promise<void> => {
+/** Resolves to true when the passphrase wiped the vault, so local data must go too. */
+export const initVault = async (passphrase: string): Promise<boolean> => {
     if (isTauri()) {
-        await invoke('init_vault', { passphrase });
+        return await invoke('init_vault', { passphrase });
+    }
+    return false;
+};
+
+export const changeVaultPassphrase = async (oldPassphrase: string, newPassphrase: string): Promise<void> => {
+    if (isTauri()) {
+        await invoke('vault_change_passphrase', { oldPassphrase, newPassphrase });
+    }
+};
+
+export const setDuressPassphrase = async (passphrase: string, duressPassphrase: string, wipeRealVault: boolean): Promise<void> => {
+    if (isTauri()) {
+        await invoke('vault_set_duress_passphrase', { passphrase, duressPassphrase, wipeRealVault });
+    }
+};
+
+export const clearDuressPassphrase = async (passphrase: string): Promise<void> => {
+    if (isTauri()) {
+        await invoke('vault_clear_duress_passphrase', { passphrase });
+    }
+};
+
+export const lockVault = async (): Promise<void> => {
+    if (isTauri()) {
+        await invoke('vault_lock');
     }
 };
 
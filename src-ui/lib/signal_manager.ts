@@ -2,8 +2,10 @@
 import { invoke } from '@tauri-apps/api/core';
 
 import { SignalStore } from './signal_store';
-import { minePoW, deriveVaultKey, sha256, fromBase64 } from './crypto';
+import { minePoW, deriveVaultKey, deriveAttachmentKey, getAttachmentKey, sha256, fromBase64 } from './crypto';
 import { secureLoad, secureStore } from './secure_storage';
+import { proxiedGet } from './utils';
+import type { BatchDecryptResult } from './types';
 
 
 function buf2hex(buffer: ArrayBuffer): string {
@@ -48,16 +50,14 @@ export class SignalManager {
 
     async refreshDecoyPool(serverUrl: string): Promise<void> {
         try {
-            const challengeRes = await fetch(`${serverUrl}/pow/challenge?type=decoy`);
+            const challengeRes = await proxiedGet(`${serverUrl}/pow/challenge?type=decoy`, 'decoy');
             if (!challengeRes.ok) return;
             const { seed, difficulty } = await challengeRes.json();
             const pow = await minePoW(seed, difficulty);
 
-            const res = await fetch(`${serverUrl}/keys/random?count=15`, {
-                headers: {
-                    'X-PoW-Seed': seed,
-                    'X-PoW-Nonce': pow.nonce.toString()
-                }
+            const res = await proxiedGet(`${serverUrl}/keys/random?count=15`, 'decoy', {
+                'X-PoW-Seed': seed,
+                'X-PoW-Nonce': pow.nonce.toString()
             });
 
             if (res.ok) {
@@ -140,7 +140,22 @@ export class SignalManager {
         });
     }
 
-    async getLocalEncryptionKey(password: string): Promise<Uint8Array | null> {
+    async getLocalEncryptionKey(): Promise<Uint8Array | null> {
+        try {
+            return await getAttachmentKey();
+        } catch (e) {
+            console.error("SignalManager: Attachment key unavailable:", e);
+            return null;
+        }
+    }
+
+    /** The header-derived key attachments were stored under before the key moved into the vault. */
+    async getHeaderEncryptionKey(password: string): Promise<Uint8Array | null> {
+        return await deriveAttachmentKey(password);
+    }
+
+    /** The PBKDF2 key attachments were stored under before they moved onto the vault header. */
+    async getLegacyEncryptionKey(password: string): Promise<Uint8Array | null> {
         const salt = await secureLoad('entropy_vault_salt');
         if (!salt) return null;
         return await deriveVaultKey(password, salt);
@@ -148,7 +163,7 @@ export class SignalManager {
 
     async getSafetyNumber(recipientHash: string, serverUrl: string): Promise<string> {
         return this.lock(async () => {
-            const response = await fetch(`${serverUrl}/keys/fetch?user=${recipientHash}`);
+            const response = await proxiedGet(`${serverUrl}/keys/fetch?user=${recipientHash}`, 'key_fetch');
             if (!response.ok) return "Unknown";
             const bundle = await response.json();
             const remoteIk = bundle.identityKey;
@@ -251,7 +266,7 @@ export class SignalManager {
                 }
             }
 
-            const response = await fetch(fetchUrl);
+            const response = await proxiedGet(fetchUrl, 'key_fetch');
             if (!response.ok) return null;
             const data = await response.json();
             bundle = data[recipientHash] || data;
@@ -265,7 +280,7 @@ export class SignalManager {
                 return null;
             }
         } else {
-            const response = await fetch(`${serverUrl}/keys/fetch?user=${recipientHash}`);
+            const response = await proxiedGet(`${serverUrl}/keys/fetch?user=${recipientHash}`, 'key_fetch');
             if (response.ok) bundle = await response.json();
         }
 
@@ -312,6 +327,12 @@ export class SignalManager {
         });
     }
 
+    async decryptBatch(items: { sender?: string, envelope: any }[]): Promise<BatchDecryptResult[]> {
+        return this.lock(async () => {
+            return await invoke('protocol_decrypt_batch', { items }) as BatchDecryptResult[];
+        });
+    }
+
     async encryptMedia(data: Uint8Array, fileName: string, fileType: string): Promise<{ ciphertext: string, bundle: any }> {
         return await invoke('protocol_encrypt_media', { data: Array.from(data), fileName, fileType });
     }
@@ -380,6 +401,7 @@ export class SignalManager {
         return this.lock(async () => {
             const salt = await secureLoad('entropy_vault_salt');
             const vaultBinary: number[] = await invoke('protocol_export_vault');
+            const vaultHeader: string | null = await invoke('protocol_export_vault_header');
 
             // Still include these for convenience/portability
             const vaultData = await invoke('dump_vault');
@@ -389,6 +411,7 @@ export class SignalManager {
                 ts: Date.now(),
                 s: salt,
                 db: vaultBinary, // The actual encrypted database bytes
+                kdf: vaultHeader, // Key derivation parameters needed to reopen it
                 vlt: vaultData,
                 cfg: { ...localStorage }
             };
@@ -405,7 +428,7 @@ export class SignalManager {
         });
     }
 
-    async importIdentity(data: Uint8Array | string): Promise<void> {
+    async importIdentity(data: Uint8Array | string, passphrase: string): Promise<void> {
         return this.lock(async () => {
             let json = "";
             const headerV2 = "ENTROPY_VAULT_V2";
@@ -426,14 +449,14 @@ export class SignalManager {
             const dbBinary = payload.db; // The raw binary database (V2+)
             const settings = payload.cfg || payload.settings;
 
-            if (salt) await secureStore('entropy_vault_salt', salt);
-
             if (dbBinary) {
-                await invoke('protocol_import_vault', { bytes: dbBinary });
+                await invoke('protocol_import_vault', { bytes: dbBinary, header: payload.kdf ?? null, passphrase });
             } else {
                 throw new Error("Invalid vault backup: Missing binary database (Legacy V1 backups are no longer supported)");
             }
 
+            if (salt) await secureStore('entropy_vault_salt', salt);
+
             if (settings) {
                 for (const k in settings) {
                     localStorage.setItem(k, settings[k]);
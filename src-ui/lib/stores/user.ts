@@ -1,6 +1,13 @@
 import { writable } from 'svelte/store';
 import type { Chat, Message, PrivacySettings } from '../types';
 
+export interface BackupStatus {
+    lastAttempt: number | null;
+    lastSuccess: number | null;
+    lastError: string | null;
+    lastFile: string | null;
+}
+
 export interface AppState {
     identityHash: string | null;
     myAlias: string | null;
@@ -16,9 +23,12 @@ export interface AppState {
     myGlobalNickname: string | null;
     nicknameExpiry: number | null;
     connectionStatus: 'disconnected' | 'connecting' | 'mining' | 'connected';
+    latencyMs: number | null;
+    torBootstrap: number | null;
     authError: string | null;
     keysMissing: boolean;
     relayUrl: string;
+    backupStatus: BackupStatus | null;
 }
 
 const initialState: AppState = {
@@ -45,9 +55,12 @@ const initialState: AppState = {
     },
     sessionToken: null,
     connectionStatus: 'disconnected',
+    latencyMs: null,
+    torBootstrap: null,
     authError: null,
     keysMissing: false,
-    relayUrl: import.meta.env.VITE_RELAY_URL || 'http://localhost:8080'
+    relayUrl: import.meta.env.VITE_RELAY_URL || 'http://localhost:8080',
+    backupStatus: null
 };
 
 export const userStore = writable<AppState>(initialState);
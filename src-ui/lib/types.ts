@@ -55,6 +55,9 @@ export interface Message {
         type: Message['type'];
     };
     linkPreview?: LinkPreview;
+    editedAt?: number;
+    isDeleted?: boolean;
+    reactions?: { reactorHash: string; emoji: string }[];
 }
 
 export interface PrivacySettings {
@@ -94,6 +97,32 @@ export interface ServerMessage {
     [key: string]: any;
 }
 
+export type NetworkStatus =
+    | { state: 'connecting' | 'connected' | 'disconnected' | 'auth_failed' }
+    | { state: 'backoff'; secs: number };
+
+export interface MessageSearchFilters {
+    peerHash?: string;
+    senderHash?: string;
+    since?: number;
+    until?: number;
+    msgType?: string;
+    hasAttachment?: boolean;
+}
+
+export interface TorBootstrap {
+    progress: number;
+    ready: boolean;
+    message: string;
+}
+
+export interface BatchDecryptResult {
+    sender: string | null;
+    plaintext: string | null;
+    message: any | null;
+    error: string | null;
+}
+
 export interface AuthPayload {
     timestamp: number;
     nonce: number;
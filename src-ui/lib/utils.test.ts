@@ -1,5 +1,5 @@
 import { describe, it, expect, vi } from 'vitest';
-import { parseLinkPreview } from './utils';
+import { parseLinkPreview, proxiedGet } from './utils';
 
 // Mock Tauri invoke
 vi.mock('@tauri-apps/api/core', () => ({
@@ -25,6 +25,7 @@ vi.mock('svelte/store', () => ({
 }));
 
 import { invoke } from '@tauri-apps/api/core';
+import { get } from 'svelte/store';
 
 describe('utils.ts', () => {
     describe('parseLinkPreview', () => {
@@ -54,4 +55,60 @@ describe('utils.ts', () => {
             });
         });
     });
+
+    describe('proxiedGet', () => {
+        it('should use fetch when routing directly', async () => {
+            global.fetch = vi.fn().mockResolvedValue({ ok: true, status: 200 });
+            await proxiedGet('https://relay.test/keys/fetch?user=ab', 'key_fetch');
+            expect(fetch).toHaveBeenCalledWith('https://relay.test/keys/fetch?user=ab', { headers: undefined });
+        });
+
+        it('should go through the embedded Tor client when available', async () => {
+            (get as any).mockReturnValueOnce({
+                privacySettings: { routingMode: 'tor', proxyUrl: '' }
+            });
+            (invoke as any).mockImplementation(async (cmd: string) =>
+                cmd === 'start_embedded_tor'
+                    ? 'socks5h://127.0.0.1:41234'
+                    : { status: 200, body: '{"hashes":["ab"]}' });
+
+            const res = await proxiedGet('https://relay.test/keys/random?count=15', 'decoy', { 'X-PoW-Nonce': '1' });
+            expect(invoke).toHaveBeenCalledWith('http_get', {
+                url: 'https://relay.test/keys/random?count=15',
+                purpose: 'decoy',
+                proxyUrl: 'socks5h://127.0.0.1:41234',
+                headers: { 'X-PoW-Nonce': '1' }
+            });
+            expect(res.ok).toBe(true);
+            expect(await res.json()).toEqual({ hashes: ['ab'] });
+        });
+
+        it('should use the system Tor daemon without an embedded client', async () => {
+            (get as any).mockReturnValueOnce({
+                privacySettings: { routingMode: 'tor', proxyUrl: '' }
+            });
+            (invoke as any).mockImplementation(async (cmd: string) =>
+                cmd === 'start_embedded_tor' ? null : { status: 404, body: '' });
+
+            const res = await proxiedGet('https://relay.test/keys/fetch?user=ab', 'key_fetch');
+            expect(invoke).toHaveBeenCalledWith('http_get', expect.objectContaining({
+                purpose: 'key_fetch',
+                proxyUrl: 'socks5://127.0.0.1:9050'
+            }));
+            expect(res.ok).toBe(false);
+        });
+
+        it('should not fall back when the embedded client fails to bootstrap', async () => {
+            (get as any).mockReturnValueOnce({
+                privacySettings: { routingMode: 'tor', proxyUrl: '' }
+            });
+            (invoke as any).mockImplementation(async (cmd: string) => {
+                if (cmd === 'start_embedded_tor') throw 'Tor bootstrap failed';
+                return { status: 200, body: '{}' };
+            });
+
+            await expect(proxiedGet('https://relay.test/keys/fetch?user=ab', 'key_fetch')).rejects.toBe('Tor bootstrap failed');
+            expect(invoke).not.toHaveBeenCalledWith('http_get', expect.anything());
+        });
+    });
 });
@@ -2,16 +2,45 @@ import { invoke } from '@tauri-apps/api/core';
 import { get } from 'svelte/store';
 import { userStore } from './stores/user';
 
+export type StreamPurpose = 'key_fetch' | 'decoy' | 'link_preview';
+
+const SYSTEM_TOR_PROXY = 'socks5://127.0.0.1:9050';
+
+// Proxy for the current routing mode. Tor uses the embedded client when the app was built
+// with one and the system daemon otherwise. The native side bootstraps the client once and
+// keeps returning the same failure until `retryEmbeddedTor`; it never falls back quietly.
+export const routingProxy = async (): Promise<string | undefined> => {
+    const { privacySettings } = get(userStore);
+    if (privacySettings.routingMode === 'direct') return undefined;
+    if (privacySettings.routingMode === 'tor') {
+        return (await invoke<string | null>('start_embedded_tor', { retry: false })) ?? SYSTEM_TOR_PROXY;
+    }
+    return privacySettings.proxyUrl;
+};
+
+export const retryEmbeddedTor = async () => {
+    await invoke('start_embedded_tor', { retry: true });
+};
+
+// GET that honours the routing mode. Proxied requests go through the native side, which
+// gives each purpose its own Tor circuit.
+export const proxiedGet = async (url: string, purpose: StreamPurpose, headers?: Record<string, string>) => {
+    const proxyUrl = await routingProxy();
+    if (!proxyUrl) return fetch(url, { headers });
+
+    const { status, body } = await invoke<{ status: number; body: string }>('http_get', { url, purpose, proxyUrl, headers });
+    return { ok: status >= 200 && status < 300, status, json: async () => JSON.parse(body) };
+};
+
 export const parseLinkPreview = async (text: string): Promise<any> => {
     const urlRegex = /(https?:\/\/[^\s]+)/g;
     const match = text.match(urlRegex);
     if (!match) return null;
 
     const url = match[0];
-    const store = get(userStore);
-    const proxyUrl = store.privacySettings.routingMode !== 'direct' ? store.privacySettings.proxyUrl : undefined;
 
     try {
+        const proxyUrl = await routingProxy();
         const preview = await invoke('get_link_preview', {
             url,
             proxyUrl
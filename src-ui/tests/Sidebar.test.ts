@@ -19,7 +19,14 @@ vi.mock('../lib/store', () => ({
     registerGlobalNickname: vi.fn().mockResolvedValue({ success: true }),
     lookupNickname: vi.fn(),
     burnAccount: vi.fn(),
-    refreshDecoys: vi.fn()
+    refreshDecoys: vi.fn(),
+    lockNow: vi.fn(),
+    getAutoLockMinutes: vi.fn().mockResolvedValue(0),
+    setAutoLockMinutes: vi.fn(),
+    configureAutoBackup: vi.fn().mockResolvedValue(true),
+    disableAutoBackup: vi.fn(),
+    runBackupNow: vi.fn(),
+    loadBackupStatus: vi.fn().mockResolvedValue(null)
 }));
 
 // Mock removed to use actual components or rely on default behavior
@@ -54,9 +61,12 @@ describe('Sidebar Component', () => {
             myGlobalNickname: null,
             nicknameExpiry: null,
             connectionStatus: 'connected',
+            latencyMs: null,
+            torBootstrap: null,
             authError: null,
             keysMissing: false,
-            relayUrl: ''
+            relayUrl: '',
+            backupStatus: null
         });
     });
 
@@ -67,6 +77,19 @@ describe('Sidebar Component', () => {
         expect(screen.getByText('1')).toBeTruthy(); // Unread count
     });
 
+    it('shows relay latency while connected', () => {
+        userStore.update(s => ({ ...s, latencyMs: 42 }));
+        render(Sidebar);
+        expect(screen.getByText('42 ms')).toBeTruthy();
+    });
+
+    it('shows Tor bootstrap progress until the client is ready', () => {
+        userStore.update(s => ({ ...s, latencyMs: 42, torBootstrap: 0.35 }));
+        render(Sidebar);
+        expect(screen.getByText('TOR 35%')).toBeTruthy();
+        expect(screen.queryByText('42 ms')).toBeNull();
+    });
+
     it('selects chat on click', async () => {
         const { component } = render(Sidebar);
         // "Alice" might appear in multiple places (name, message content, etc.)
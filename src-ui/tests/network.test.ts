@@ -4,6 +4,7 @@ import { invoke } from '@tauri-apps/api/core';
 
 vi.mock('@tauri-apps/api/core', () => ({
     invoke: vi.fn(),
+    Channel: class { onmessage: (msg: unknown) => void = () => { }; },
 }));
 
 vi.mock('@tauri-apps/api/event', () => ({
@@ -52,7 +53,8 @@ describe('NetworkLayer', () => {
         expect(invoke).toHaveBeenCalledWith('connect_network', expect.objectContaining({
             relayUrl: expect.stringContaining('ws://localhost:8080/ws'),
             bearerToken: expect.anything(), // should match sessionToken
-            proxyUrl: undefined
+            proxyUrl: undefined,
+            binaryChannel: expect.anything()
         }));
     });
 
@@ -61,4 +63,16 @@ describe('NetworkLayer', () => {
         network.sendJSON({ type: 'test' });
         expect((network as any).messageQueue.length).toBe(1);
     });
+
+    it('should stop the native connection and drop queued messages on disconnect', async () => {
+        vi.mocked(invoke).mockResolvedValue(undefined);
+        (network as any).isConnected = true;
+        network.sendJSON({ type: 'test' });
+
+        await network.disconnect();
+        expect(invoke).toHaveBeenCalledWith('disconnect_network');
+        expect((network as any).messageQueue.length).toBe(0);
+        expect((network as any).isConnected).toBe(false);
+        expect((network as any).heartbeatInterval).toBeFalsy();
+    });
 });
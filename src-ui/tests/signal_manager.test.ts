@@ -11,6 +11,8 @@ vi.mock('../lib/crypto', () => ({
     sha256: vi.fn().mockResolvedValue('hash'),
     fromBase64: vi.fn().mockReturnValue(new Uint8Array(32)),
     deriveVaultKey: vi.fn(),
+    deriveAttachmentKey: vi.fn(),
+    getAttachmentKey: vi.fn(),
 }));
 
 vi.mock('../lib/secure_storage', () => ({
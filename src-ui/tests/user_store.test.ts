@@ -28,9 +28,12 @@ describe('userStore', () => {
             },
             sessionToken: null,
             connectionStatus: 'disconnected',
+            latencyMs: null,
+            torBootstrap: null,
             authError: null,
             keysMissing: false,
-            relayUrl: 'http://localhost:8080'
+            relayUrl: 'http://localhost:8080',
+            backupStatus: null
         });
     });
 